@@ -1,136 +1,6781 @@
 #[macro_use]
 extern crate serde;
-use candid::{Decode, Encode};
-use ic_cdk::api::time;
+use candid::{Decode, Encode, IDLArgs, IDLValue, Principal};
+#[cfg(not(test))]
+use ic_cdk::api::{caller, is_controller, time};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
-use std::{borrow::Cow, cell::RefCell};
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    cmp::{Ordering, Reverse},
+    collections::{BTreeMap, BTreeSet, BinaryHeap, VecDeque},
+    ops::Bound,
+};
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type IdCell = Cell<u64, Memory>;
+type RefundEntry = ((u64, u64), Refund);
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
 struct Expense {
     id: u64,
+    owner: Principal, // Principal of the caller who created the expense
     description: String,
-    amount: f64,
-    date: u64, // Timestamp of when the expense was made
+    amount: i64, // Amount in minor units (cents) to keep totals exact
+    date: u64,   // Timestamp of when the expense was made, in nanoseconds since the epoch
+    category_id: Option<u64>,
+    currency: String, // ISO 4217 currency code, e.g. "USD"
+    merchant: Option<String>,
+    note: Option<String>,
+    payment_method: Option<PaymentMethod>,
+    tags: Vec<String>, // Lowercase, deduplicated labels
+    entry_type: EntryType,
+    splits: Vec<Split>, // Who owes what; empty when the expense isn't shared
+    // Parts of `amount` attributed to other categories in reports; empty when
+    // the whole amount belongs to `category_id`
+    category_splits: Vec<CategorySplit>,
+    receipt_url: Option<String>, // Link to a receipt stored elsewhere
+    refunded_total: i64,         // Sum of refunds against `amount`, in minor units
+    status: ExpenseStatus,       // Only approved expenses count towards totals
+    reimbursable: bool,          // Paid on someone else's behalf, e.g. business travel
+    reimbursed_at: Option<u64>,  // When a reimbursable expense was paid back
     created_at: u64,
     updated_at: Option<u64>,
+    version: u64, // Incremented on every change, starting at 0
+    archived: bool,
+    archived_at: Option<u64>,
+}
+
+// Supplies the values of fields missing from records written before those
+// fields existed; see `decode_with_defaults`
+impl Default for Expense {
+    fn default() -> Self {
+        Expense {
+            id: 0,
+            owner: Principal::anonymous(),
+            description: String::new(),
+            amount: 0,
+            date: 0,
+            category_id: None,
+            currency: String::new(),
+            merchant: None,
+            note: None,
+            payment_method: None,
+            tags: Vec::new(),
+            entry_type: EntryType::Expense,
+            splits: Vec::new(),
+            category_splits: Vec::new(),
+            receipt_url: None,
+            refunded_total: 0,
+            // Records from before the approval workflow were already counted
+            status: ExpenseStatus::Approved,
+            reimbursable: false,
+            reimbursed_at: None,
+            created_at: 0,
+            updated_at: None,
+            version: 0,
+            archived: false,
+            archived_at: None,
+        }
+    }
+}
+
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord,
+)]
+enum PaymentMethod {
+    Cash,
+    Credit,
+    Debit,
+    BankTransfer,
+    Other,
+}
+
+// One participant's part of a shared expense
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Split {
+    participant: String,
+    share: i64, // Share in minor units (cents)
+}
+
+// The part of an expense that belongs to one category
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CategorySplit {
+    category_id: u64,
+    amount: i64, // Amount in minor units (cents)
+}
+
+// Whether a ledger entry is money going out or coming in
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+enum EntryType {
+    Expense,
+    Income,
+}
+
+// Where an expense is in the approval workflow
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+enum ExpenseStatus {
+    Pending,
+    Approved,
+    Rejected,
 }
 
 // Implementing `Storable` trait for `Expense`
 impl Storable for Expense {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        decode_with_defaults(bytes.as_ref())
     }
 }
 
-// Implementing `BoundedStorable` trait for `Expense`
+// Implementing `BoundedStorable` trait for `Expense`.
+// The bound is recorded in stable memory when the map is created, and the map
+// refuses to load with a larger one, so it cannot simply be raised later.
+// Payloads are checked against it in `validate_expense_payload` instead.
 impl BoundedStorable for Expense {
     const MAX_SIZE: u32 = 1024;
     const IS_FIXED_SIZE: bool = false;
 }
 
-thread_local! {
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
-        MemoryManager::init(DefaultMemoryImpl::default())
-    );
+const MAX_CATEGORY_NAME_LEN: usize = 100;
+const MAX_RULE_KEYWORD_LEN: usize = 50;
+const MAX_RULES_PER_OWNER: usize = 100;
+const MAX_MERCHANT_LEN: usize = 200;
+const MAX_NOTE_LEN: usize = 200;
+const MAX_RECEIPT_URL_LEN: usize = 200;
+const RECEIPT_URL_SCHEMES: [&str; 3] = ["http://", "https://", "ipfs://"];
+const MAX_TAGS: usize = 10;
+const MAX_TAG_LEN: usize = 30;
+const MAX_SPLITS: usize = 10;
+const MAX_PARTICIPANT_LEN: usize = 30;
+const MAX_CATEGORY_SPLITS: usize = 5;
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+// Amounts are stored in minor units (cents), so no finer rounding is possible
+const MAX_DECIMAL_PLACES: u8 = 2;
+const MAX_AMOUNT_EPSILON: f64 = 1.0;
+// Stored dates below these are taken to be in seconds or milliseconds. Both
+// are year 2286 in their unit, while as nanoseconds they would fall within
+// the first hours of 1970.
+const SECONDS_DATE_THRESHOLD: u64 = 10_000_000_000;
+const MILLIS_DATE_THRESHOLD: u64 = 10_000_000_000_000;
+// Bumped whenever `post_upgrade` gains a one-time migration of stored data
+const CURRENT_MIGRATION_VERSION: u64 = 1;
+const MIN_YEAR: u32 = 1970;
+const MAX_YEAR: u32 = 2200;
+const MAX_PER_PAGE: usize = 100;
+const MAX_ALL_EXPENSES: u64 = 5_000;
+const MAX_RECURRING_CATCH_UP: usize = 366;
+const MAX_BATCH_SIZE: usize = 100;
+const MAX_IMPORT_SIZE: usize = 1_000;
+const MAX_IMPORT_JSON_BYTES: usize = 1024 * 1024;
+const MAX_HISTORY_PER_EXPENSE: usize = 50;
+const MAX_ACTIVITY_EVENTS: u64 = 500;
+const MAX_COMMENTS_PER_EXPENSE: usize = 100;
+const MAX_COMMENT_LEN: usize = 500;
+const MAX_TOP_EXPENSES: usize = 100;
+const MAX_SUGGESTIONS: usize = 20;
+const MAX_HISTOGRAM_BUCKETS: u64 = 200;
+const MAX_SORT_KEYS: usize = 4;
+// Categories with fewer expenses are too small for meaningful statistics
+const MIN_ANOMALY_SAMPLE: usize = 3;
+const MAX_WEEKS: u32 = 104;
+const MAX_RECENT_DAYS: u64 = 3_650;
+const MAX_DISTINCT_MERCHANTS: usize = 500;
+// Heuristics behind `add_expense_checked` warnings, in minor units and nanoseconds
+const SMALL_AMOUNT_WARNING: i64 = 100;
+const LARGE_AMOUNT_WARNING: i64 = 1_000_000;
+const OLD_DATE_WARNING_NANOS: u64 = 365 * NANOS_PER_DAY;
+// Stands in for "no category" where a category id is required
+const UNCATEGORIZED_ID: u64 = u64::MAX;
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+const MAX_UTC_OFFSET_MINUTES: i32 = 14 * 60;
+const TREND_FLAT_THRESHOLD: f64 = 0.01; // Slopes under a cent per day count as flat
+const DUPLICATE_WINDOW_NANOS: u64 = 60 * 1_000_000_000;
+const CLEAR_ALL_CONFIRMATION: &str = "CONFIRM_DELETE_ALL";
+const RESTORE_CONFIRMATION: &str = "CONFIRM_RESTORE";
+// Every snapshot starts with the magic bytes followed by a one-byte version
+const SNAPSHOT_MAGIC: &[u8] = b"EXPSNAP";
+const SNAPSHOT_VERSION: u8 = 1;
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 64;
+const MAX_IDEMPOTENCY_KEYS: u64 = 10_000;
+const IDEMPOTENCY_KEY_TTL_NANOS: u64 = NANOS_PER_DAY;
 
-    static ID_COUNTER: RefCell<IdCell> = RefCell::new(
-        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), 0)
-            .expect("Cannot create a counter")
-    );
+// Display conventions for well-known currencies:
+// (code, symbol, shows cents, thousands separator, decimal separator)
+const CURRENCY_FORMATS: &[(&str, &str, bool, char, char)] = &[
+    ("USD", "$", true, ',', '.'),
+    ("EUR", "€", true, ',', '.'),
+    ("GBP", "£", true, ',', '.'),
+    ("JPY", "¥", false, ',', '.'),
+    ("IDR", "Rp", false, '.', ','),
+];
 
-    static STORAGE: RefCell<StableBTreeMap<u64, Expense, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
-    ));
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Category {
+    id: u64,
+    owner: Principal, // Principal of the caller who created the category
+    name: String,
+    created_at: u64,
 }
 
-#[derive(candid::CandidType, Serialize, Deserialize, Default)]
-struct ExpensePayload {
+// Implementing `Storable` trait for `Category`
+impl Storable for Category {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implementing `BoundedStorable` trait for `Category`
+impl BoundedStorable for Category {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Assigns `category_id` to new expenses without a category whose description
+// contains `keyword`, ignoring case
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CategoryRule {
+    id: u64,
+    owner: Principal,
+    keyword: String, // Stored lowercase
+    category_id: u64,
+}
+
+// Implementing `Storable` trait for `CategoryRule`
+impl Storable for CategoryRule {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implementing `BoundedStorable` trait for `CategoryRule`
+impl BoundedStorable for CategoryRule {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct RecurringTemplate {
+    id: u64,
+    owner: Principal,
     description: String,
-    amount: f64,
-    date: u64, // Timestamp of the expense
+    amount: i64, // Amount in minor units (cents)
+    currency: String,
+    interval_days: u64,
+    next_due: u64, // Timestamp of the next expense to generate, in nanoseconds
+    category_id: Option<u64>,
 }
 
-#[ic_cdk::query]
-fn get_expense(id: u64) -> Result<Expense, Error> {
-    match _get_expense(&id) {
-        Some(expense) => Ok(expense),
-        None => Err(Error::NotFound {
-            msg: format!("Expense with id={} not found", id),
-        }),
+// Implementing `Storable` trait for `RecurringTemplate`
+impl Storable for RecurringTemplate {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
     }
 }
 
-#[ic_cdk::update]
-fn add_expense(payload: ExpensePayload) -> Option<Expense> {
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("Cannot increment id counter");
+// Implementing `BoundedStorable` trait for `RecurringTemplate`
+impl BoundedStorable for RecurringTemplate {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
 
-    let new_expense = Expense {
-        id,
-        description: payload.description,
-        amount: payload.amount,
-        date: payload.date,
-        created_at: time(),
-        updated_at: None,
-    };
-    do_insert(&new_expense);
-    Some(new_expense)
+// A bill expected in the future. Marking it paid records it as an expense.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PlannedExpense {
+    id: u64,
+    owner: Principal,
+    description: String,
+    amount: i64, // Amount in minor units (cents)
+    currency: String,
+    due_date: u64, // Timestamp the bill is due, in nanoseconds
+    paid: bool,
+    expense_id: Option<u64>, // Expense recorded when the bill was paid
 }
 
-#[ic_cdk::update]
-fn update_expense(id: u64, payload: ExpensePayload) -> Result<Expense, Error> {
-    match STORAGE.with(|service| service.borrow().get(&id)) {
-        Some(mut expense) => {
-            expense.description = payload.description;
-            expense.amount = payload.amount;
-            expense.date = payload.date;
-            expense.updated_at = Some(time());
-            do_insert(&expense);
-            Ok(expense)
+// Implementing `Storable` trait for `PlannedExpense`
+impl Storable for PlannedExpense {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implementing `BoundedStorable` trait for `PlannedExpense`
+impl BoundedStorable for PlannedExpense {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+enum ActivityKind {
+    Added,
+    Updated,
+    Deleted,
+}
+
+// One write to an expense, for the cross-expense activity feed
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ActivityEvent {
+    kind: ActivityKind,
+    expense_id: u64,
+    owner: Principal,
+    timestamp: u64,
+    amount: i64, // Amount in minor units (cents) after the write, or before a deletion
+}
+
+// Implementing `Storable` trait for `ActivityEvent`
+impl Storable for ActivityEvent {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implementing `BoundedStorable` trait for `ActivityEvent`
+impl BoundedStorable for ActivityEvent {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ExpenseHistory {
+    expense_id: u64,
+    changed_at: u64,
+    old_amount: i64, // Amounts in minor units (cents)
+    new_amount: i64,
+    old_description: String,
+    new_description: String,
+}
+
+// Implementing `Storable` trait for `ExpenseHistory`
+impl Storable for ExpenseHistory {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implementing `BoundedStorable` trait for `ExpenseHistory`.
+// Each entry holds two descriptions, each bounded by the expense record itself.
+impl BoundedStorable for ExpenseHistory {
+    const MAX_SIZE: u32 = 2 * Expense::MAX_SIZE + 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Comment {
+    author: Principal,
+    text: String,
+    created_at: u64,
+}
+
+// Implementing `Storable` trait for `Comment`
+impl Storable for Comment {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implementing `BoundedStorable` trait for `Comment`.
+// The text is capped in characters, and a character takes up to four bytes.
+impl BoundedStorable for Comment {
+    const MAX_SIZE: u32 = 4 * MAX_COMMENT_LEN as u32 + 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Refund {
+    expense_id: u64,
+    amount: i64, // Amount in minor units (cents)
+    date: u64,   // Timestamp of the refund, in nanoseconds
+    created_at: u64,
+}
+
+// Implementing `Storable` trait for `Refund`
+impl Storable for Refund {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implementing `BoundedStorable` trait for `Refund`
+impl BoundedStorable for Refund {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Budget {
+    category_id: u64,
+    monthly_limit: i64, // Limit in minor units (cents)
+}
+
+// Implementing `Storable` trait for `Budget`
+impl Storable for Budget {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implementing `BoundedStorable` trait for `Budget`
+impl BoundedStorable for Budget {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Unit that clients send `date` values in; they are stored as nanoseconds
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+enum DateUnit {
+    Nanos,
+    Millis,
+    Seconds,
+}
+
+// Canister-wide configuration, editable by controllers without a redeploy
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Settings {
+    base_currency: String, // Currency that exchange rates convert into
+    default_per_page: usize,
+    allow_future_dates: bool,
+    max_amount: f64,             // Largest amount a single expense may have
+    max_expenses_per_owner: u64, // Archived expenses count towards the limit
+    approver: Option<Principal>, // Who reviews expenses; controllers when unset
+    rate_limit_max_calls: u64,   // Expenses a caller may add within the window
+    rate_limit_window_secs: u64,
+    date_unit: DateUnit, // Unit of incoming expense and refund dates
+    decimal_places: u8,  // Incoming expense amounts are rounded to this many places
+    amount_epsilon: f64, // Largest difference at which two amounts count as equal
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            base_currency: "USD".to_string(),
+            default_per_page: 20,
+            allow_future_dates: false,
+            max_amount: 1_000_000_000.0,
+            max_expenses_per_owner: 10_000,
+            approver: None,
+            rate_limit_max_calls: 20,
+            rate_limit_window_secs: 60,
+            date_unit: DateUnit::Nanos,
+            decimal_places: 2,
+            // Half a cent, so amounts are equal exactly when their cents are
+            amount_epsilon: 0.005,
         }
-        None => Err(Error::NotFound {
-            msg: format!("Couldn't update expense with id={}. Expense not found.", id),
-        }),
     }
 }
 
-#[ic_cdk::update]
-fn delete_expense(id: u64) -> Result<Expense, Error> {
-    match STORAGE.with(|service| service.borrow_mut().remove(&id)) {
-        Some(expense) => Ok(expense),
-        None => Err(Error::NotFound {
-            msg: format!("Couldn't delete expense with id={}. Expense not found.", id),
-        }),
+// Implementing `Storable` trait for `Settings`
+impl Storable for Settings {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_with_defaults(bytes.as_ref())
     }
 }
 
-// Helper function to perform the insertion
-fn do_insert(expense: &Expense) {
-    STORAGE.with(|service| service.borrow_mut().insert(expense.id, expense.clone()));
+// Idempotency key scoped to the principal that sent it, used as a stable map key
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct IdempotencyKey {
+    owner: Principal,
+    key: String,
 }
 
-#[derive(candid::CandidType, Deserialize, Serialize)]
-enum Error {
-    NotFound { msg: String },
+// Implementing `Storable` trait for `IdempotencyKey`
+impl Storable for IdempotencyKey {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
 }
 
-// Helper method to get an expense by id
-fn _get_expense(id: &u64) -> Option<Expense> {
-    STORAGE.with(|service| service.borrow().get(id))
+// Implementing `BoundedStorable` trait for `IdempotencyKey`
+impl BoundedStorable for IdempotencyKey {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
 }
 
-// Export candid for the canister
-ic_cdk::export_candid!();
+// Principal wrapper used as a stable map key
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct OwnerKey(Principal);
+
+// Implementing `Storable` trait for `OwnerKey`
+impl Storable for OwnerKey {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_slice())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        OwnerKey(Principal::from_slice(bytes.as_ref()))
+    }
+}
+
+// Implementing `BoundedStorable` trait for `OwnerKey`
+impl BoundedStorable for OwnerKey {
+    const MAX_SIZE: u32 = 29;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Three-letter ISO 4217 currency code, used as a stable map key
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct CurrencyCode(String);
+
+// Implementing `Storable` trait for `CurrencyCode`
+impl Storable for CurrencyCode {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        CurrencyCode(String::from_utf8(bytes.into_owned()).unwrap())
+    }
+}
+
+// Implementing `BoundedStorable` trait for `CurrencyCode`
+impl BoundedStorable for CurrencyCode {
+    const MAX_SIZE: u32 = 3;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+// Stable memory layout. Every structure owns exactly one virtual memory
+// region; ids must never be reused or renumbered, only appended.
+// All canister state lives in these regions, so nothing has to be
+// serialized in `pre_upgrade`.
+const ID_COUNTER_MEMORY_ID: MemoryId = MemoryId::new(0);
+const STORAGE_MEMORY_ID: MemoryId = MemoryId::new(1);
+const CATEGORY_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(2);
+const CATEGORY_ID_COUNTER_MEMORY_ID: MemoryId = MemoryId::new(3);
+const EXCHANGE_RATE_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(4);
+const RECURRING_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(5);
+const RECURRING_ID_COUNTER_MEMORY_ID: MemoryId = MemoryId::new(6);
+const HISTORY_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(7);
+const BUDGET_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(8);
+const SETTINGS_MEMORY_ID: MemoryId = MemoryId::new(9);
+const PLANNED_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(10);
+const PLANNED_ID_COUNTER_MEMORY_ID: MemoryId = MemoryId::new(11);
+const IDEMPOTENCY_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(12);
+const ALLOWED_CURRENCY_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(13);
+const OWNER_COUNT_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(14);
+const COMMENT_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(15);
+const REFUND_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(16);
+const LOCKED_THROUGH_MEMORY_ID: MemoryId = MemoryId::new(17);
+const CATEGORY_RULE_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(18);
+const CATEGORY_RULE_ID_COUNTER_MEMORY_ID: MemoryId = MemoryId::new(19);
+const ACTIVITY_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(20);
+const MIGRATION_VERSION_MEMORY_ID: MemoryId = MemoryId::new(21);
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
+        MemoryManager::init(DefaultMemoryImpl::default())
+    );
+
+    static ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(ID_COUNTER_MEMORY_ID)), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static STORAGE: RefCell<StableBTreeMap<u64, Expense, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(STORAGE_MEMORY_ID))
+    ));
+
+    static CATEGORY_STORAGE: RefCell<StableBTreeMap<u64, Category, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(CATEGORY_STORAGE_MEMORY_ID))
+    ));
+
+    static CATEGORY_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(CATEGORY_ID_COUNTER_MEMORY_ID)), 0)
+            .expect("Cannot create a category counter")
+    );
+
+    static EXCHANGE_RATE_STORAGE: RefCell<StableBTreeMap<CurrencyCode, f64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(EXCHANGE_RATE_STORAGE_MEMORY_ID))
+    ));
+
+    static RECURRING_STORAGE: RefCell<StableBTreeMap<u64, RecurringTemplate, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(RECURRING_STORAGE_MEMORY_ID))
+    ));
+
+    static RECURRING_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(RECURRING_ID_COUNTER_MEMORY_ID)), 0)
+            .expect("Cannot create a recurring template counter")
+    );
+
+    // Keyed by (expense id, sequence number within that expense's history)
+    static HISTORY_STORAGE: RefCell<StableBTreeMap<(u64, u64), ExpenseHistory, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(HISTORY_STORAGE_MEMORY_ID))
+    ));
+
+    static BUDGET_STORAGE: RefCell<StableBTreeMap<u64, Budget, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(BUDGET_STORAGE_MEMORY_ID))
+    ));
+
+    static SETTINGS: RefCell<Cell<Settings, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(SETTINGS_MEMORY_ID)), Settings::default())
+            .expect("Cannot create the settings cell")
+    );
+
+    static PLANNED_STORAGE: RefCell<StableBTreeMap<u64, PlannedExpense, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(PLANNED_STORAGE_MEMORY_ID))
+    ));
+
+    static PLANNED_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(PLANNED_ID_COUNTER_MEMORY_ID)), 0)
+            .expect("Cannot create a planned expense counter")
+    );
+
+    // Values are (expense id, time the key was first seen)
+    static IDEMPOTENCY_STORAGE: RefCell<StableBTreeMap<IdempotencyKey, (u64, u64), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(IDEMPOTENCY_STORAGE_MEMORY_ID))
+    ));
+
+    // Used as a set; an empty set allows every currency
+    static ALLOWED_CURRENCY_STORAGE: RefCell<StableBTreeMap<CurrencyCode, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(ALLOWED_CURRENCY_STORAGE_MEMORY_ID))
+    ));
+
+    // Number of stored expenses per owner, archived ones included, so quotas
+    // can be checked without scanning `STORAGE`
+    static OWNER_COUNT_STORAGE: RefCell<StableBTreeMap<OwnerKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(OWNER_COUNT_STORAGE_MEMORY_ID))
+    ));
+
+    // Comments keyed by (expense id, sequence number), oldest first
+    static COMMENT_STORAGE: RefCell<StableBTreeMap<(u64, u64), Comment, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(COMMENT_STORAGE_MEMORY_ID))
+    ));
+
+    // Refunds keyed by (expense id, sequence number), oldest first
+    static REFUND_STORAGE: RefCell<StableBTreeMap<(u64, u64), Refund, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(REFUND_STORAGE_MEMORY_ID))
+    ));
+
+    // Expenses dated at or before this timestamp can no longer be changed;
+    // 0 means no period is locked
+    static LOCKED_THROUGH: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(LOCKED_THROUGH_MEMORY_ID)), 0)
+            .expect("Cannot create the period lock")
+    );
+
+    static CATEGORY_RULE_STORAGE: RefCell<StableBTreeMap<u64, CategoryRule, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(CATEGORY_RULE_STORAGE_MEMORY_ID))
+    ));
+
+    static CATEGORY_RULE_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(CATEGORY_RULE_ID_COUNTER_MEMORY_ID)), 0)
+            .expect("Cannot create a category rule counter")
+    );
+
+    // Writes to expenses by sequence number, oldest first, keeping only the
+    // latest `MAX_ACTIVITY_EVENTS`
+    static ACTIVITY_STORAGE: RefCell<StableBTreeMap<u64, ActivityEvent, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(ACTIVITY_STORAGE_MEMORY_ID))
+    ));
+
+    // The last one-time migration `post_upgrade` ran on the stored data
+    static MIGRATION_VERSION: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MIGRATION_VERSION_MEMORY_ID)), 0)
+            .expect("Cannot create the migration version")
+    );
+
+    // Times of each caller's most recently added expenses, oldest first. Kept on
+    // the heap: losing it on upgrade only resets the rate limiter.
+    static RECENT_ADDS: RefCell<BTreeMap<Principal, VecDeque<u64>>> =
+        const { RefCell::new(BTreeMap::new()) };
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ExpensePayload {
+    description: String,
+    amount: f64,
+    date: u64, // Timestamp of the expense, in nanoseconds since the epoch
+    category_id: Option<u64>,
+    // ISO 4217 currency code, e.g. "USD"; the base currency when omitted or empty
+    currency: Option<String>,
+    merchant: Option<String>,
+    note: Option<String>,
+    payment_method: Option<PaymentMethod>,
+    splits: Option<Vec<SplitPayload>>, // Shares must add up to `amount`
+    category_splits: Option<Vec<CategorySplitPayload>>, // Must add up to `amount`
+    // Retrying with the same key returns the expense created the first time
+    idempotency_key: Option<String>,
+    receipt_url: Option<String>, // http://, https://, or ipfs:// link
+    // Not reimbursable when omitted on add; unchanged when omitted on update
+    reimbursable: Option<bool>,
+}
+
+// Fields to change with `patch_expense`; `None` leaves a field as it is
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ExpensePatch {
+    description: Option<String>,
+    amount: Option<f64>,
+    date: Option<u64>,
+}
+
+// One step of an `apply_operations` batch
+#[derive(candid::CandidType, Serialize, Deserialize)]
+enum ExpenseOp {
+    Add {
+        payload: ExpensePayload,
+    },
+    Update {
+        id: u64,
+        expected_version: u64,
+        payload: ExpensePayload,
+        if_unmodified_since: Option<u64>, // Same as for `update_expense`
+    },
+    Delete {
+        id: u64, // Like with `delete_expense`, the expense must be archived first
+    },
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+enum OpResult {
+    Added { expense: Expense },
+    Updated { expense: Expense },
+    Deleted { expense: Expense },
+}
+
+// An `ExpenseOp` that passed every check, with the stored expense it changes
+enum CheckedOp {
+    Add(ExpensePayload),
+    Update(Box<Expense>, ExpensePayload),
+    Delete(Expense),
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SplitPayload {
+    participant: String,
+    share: f64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CategorySplitPayload {
+    category_id: u64,
+    amount: f64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct RecurringPayload {
+    description: String,
+    amount: f64,
+    currency: String,
+    interval_days: u64,
+    next_due: u64, // Timestamp of the first expense to generate, in nanoseconds
+    category_id: Option<u64>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct PlannedPayload {
+    description: String,
+    amount: f64,
+    currency: String,
+    due_date: u64, // Timestamp the bill is due, in nanoseconds
+}
+
+// Every `Some` field narrows the result; all fields are combined with AND
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ExpenseFilter {
+    start_date: Option<u64>,
+    end_date: Option<u64>,
+    min_amount: Option<f64>,
+    max_amount: Option<f64>,
+    category_id: Option<u64>,
+    description_contains: Option<String>,
+}
+
+impl ExpenseFilter {
+    fn matches(&self, expense: &Expense) -> bool {
+        let amount = from_minor_units(expense.amount);
+        self.start_date.is_none_or(|start| expense.date >= start)
+            && self.end_date.is_none_or(|end| expense.date <= end)
+            && self.min_amount.is_none_or(|min| amount >= min)
+            && self.max_amount.is_none_or(|max| amount <= max)
+            && self
+                .category_id
+                .is_none_or(|category_id| expense.category_id == Some(category_id))
+            && self.description_contains.as_ref().is_none_or(|text| {
+                expense
+                    .description
+                    .to_lowercase()
+                    .contains(&text.trim().to_lowercase())
+            })
+    }
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+enum SortField {
+    Amount,
+    Date,
+    CreatedAt,
+    Category, // Uncategorized expenses sort before any category
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+enum SortOrder {
+    Asc,
+    Desc,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+struct SortKey {
+    field: SortField,
+    order: SortOrder,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct PaginatedExpenses {
+    items: Vec<Expense>,
+    total: u64,
+    page: usize,
+    per_page: usize,
+    total_pages: usize,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ExpensePage {
+    items: Vec<Expense>,
+    next_cursor: Option<u64>, // Pass back as `cursor_id` for the next page; None on the last page
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct MonthlyTotal {
+    year: u32,
+    month: u32,
+    total: f64,
+    count: u64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct DailyTotal {
+    day: u32,  // Day of the month, in the requested UTC offset
+    date: u64, // Start of that day, in nanoseconds since the epoch
+    total: f64,
+    count: u64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct WeekdayTotal {
+    weekday: u8, // 0 = Monday through 6 = Sunday, in UTC
+    total: f64,
+    count: u64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+enum TrendDirection {
+    Increasing,
+    Decreasing,
+    Flat,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct TrendResult {
+    slope: f64, // Change in daily spending per day
+    direction: TrendDirection,
+    daily_points: Vec<DailyTotal>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct WeeklyTotal {
+    week_start: u64,
+    week_end: u64, // Inclusive, one nanosecond before the next week starts
+    total: f64,
+    count: u64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct CategoryAverage {
+    category_id: Option<u64>, // None for expenses without a category
+    category_name: String,
+    average: f64,
+    count: u64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct CategoryShare {
+    category_id: Option<u64>, // None for expenses without a category
+    category_name: String,
+    total: f64,
+    percentage: f64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct BudgetStatus {
+    limit: f64,
+    spent: f64,
+    remaining: f64,
+    over_budget: bool,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct PeriodComparison {
+    period_a_total: f64,
+    period_b_total: f64,
+    difference: f64,                // period A minus period B
+    percentage_change: Option<f64>, // None when period B has no spending
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct BalanceEntry {
+    expense_id: u64,
+    date: u64,
+    amount: f64,
+    entry_type: EntryType,
+    running_total: f64, // Expenses minus income up to and including this entry
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct CheckedExpense {
+    expense: Expense,
+    warnings: Vec<String>, // Advisory only; the expense has been stored
+}
+
+// An occurrence of a recurring template that could not be recorded
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct RecurringFailure {
+    template_id: u64,
+    due_date: u64, // The skipped occurrence, in nanoseconds
+    error: Error,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct MaterializeResult {
+    created: Vec<Expense>,
+    failures: Vec<RecurringFailure>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct TotalSummary {
+    total: f64,
+    count: u64,
+    is_empty: bool,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct ExpenseStats {
+    count: u64,
+    total: f64,
+    average: f64,
+    min: f64,
+    max: f64,
+    median: f64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct HistogramBucket {
+    range_start: f64,
+    range_end: f64, // Exclusive
+    count: u64,
+    total: f64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct Quartiles {
+    q1: f64,
+    median: f64,
+    q3: f64,
+}
+
+// Body of an `export_snapshot` blob. Expenses are kept in their stored
+// encoding so that snapshots from older versions decode with defaults for
+// fields added since.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct Snapshot {
+    expenses: Vec<Vec<u8>>,
+    categories: Vec<Category>,
+    next_expense_id: u64,
+    next_category_id: u64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct TrackingSpan {
+    first_date: u64,
+    last_date: u64,
+    span_days: u64, // UTC calendar days from the first to the last date, both included
+    total_count: u64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct CanisterStats {
+    total_expenses: u64, // Every stored expense of every user, archived ones included
+    total_categories: u64,
+    next_id: u64,
+    estimated_bytes_used: u64, // Size of stable memory, which grows in 64 KiB pages
+    cycle_balance: u64,
+}
+
+// Everything a dashboard shows for a date range, computed in one call
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct PeriodSummary {
+    total: f64,
+    count: u64,
+    average: f64,
+    min: f64,
+    max: f64,
+    top_category: Option<CategoryShare>, // None when the range has no expenses
+    daily_totals: Vec<DailyTotal>,
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    // Make sure the counters never hand out an id that is already taken,
+    // even if a counter region was re-initialized by the upgrade.
+    let next_expense_id = STORAGE.with(|service| {
+        service
+            .borrow()
+            .last_key_value()
+            .map_or(0, |(id, _)| id + 1)
+    });
+    restore_counter(&ID_COUNTER, next_expense_id);
+
+    let next_category_id = CATEGORY_STORAGE.with(|service| {
+        service
+            .borrow()
+            .last_key_value()
+            .map_or(0, |(id, _)| id + 1)
+    });
+    restore_counter(&CATEGORY_ID_COUNTER, next_category_id);
+
+    let next_recurring_id = RECURRING_STORAGE.with(|service| {
+        service
+            .borrow()
+            .last_key_value()
+            .map_or(0, |(id, _)| id + 1)
+    });
+    restore_counter(&RECURRING_ID_COUNTER, next_recurring_id);
+
+    let next_planned_id = PLANNED_STORAGE.with(|service| {
+        service
+            .borrow()
+            .last_key_value()
+            .map_or(0, |(id, _)| id + 1)
+    });
+    restore_counter(&PLANNED_ID_COUNTER, next_planned_id);
+
+    let next_rule_id = CATEGORY_RULE_STORAGE.with(|service| {
+        service
+            .borrow()
+            .last_key_value()
+            .map_or(0, |(id, _)| id + 1)
+    });
+    restore_counter(&CATEGORY_RULE_ID_COUNTER, next_rule_id);
+
+    // Both scan every stored record, so they run only once per canister
+    let migrated = MIGRATION_VERSION.with(|cell| *cell.borrow().get());
+    if migrated < CURRENT_MIGRATION_VERSION {
+        rebuild_owner_counts();
+        normalize_stored_dates();
+        MIGRATION_VERSION
+            .with(|cell| cell.borrow_mut().set(CURRENT_MIGRATION_VERSION))
+            .expect("Cannot store the migration version");
+    }
+}
+
+#[ic_cdk::query]
+fn get_expense(id: u64) -> Result<Expense, Error> {
+    match _get_expense(&id) {
+        Some(expense) => {
+            ensure_owner(&expense)?;
+            Ok(expense)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Expense with id={} not found", id),
+        }),
+    }
+}
+
+// The expense's amount formatted for display in its own currency
+#[ic_cdk::query]
+fn format_expense_amount(id: u64) -> Result<String, Error> {
+    let expense = get_expense(id)?;
+    Ok(format_amount(expense.amount, &expense.currency))
+}
+
+#[ic_cdk::query]
+fn get_my_expenses() -> Vec<Expense> {
+    newest_first(_get_active_expenses(&caller()))
+}
+
+// Returns all of the caller's expenses in one reply. Large ledgers are
+// refused rather than risk exceeding the IC's response size limit.
+#[ic_cdk::query]
+fn get_all_expenses() -> Result<Vec<Expense>, Error> {
+    let count = count_expenses();
+    if count > MAX_ALL_EXPENSES {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "You have {} expenses, more than the {} that can be returned at once. Use get_paginated_expenses instead.",
+                count, MAX_ALL_EXPENSES
+            ),
+        });
+    }
+    Ok(newest_first(_get_active_expenses(&caller())))
+}
+
+// Canister-wide figures for monitoring memory growth and funding. Counts come
+// from map lengths, so this stays cheap however much is stored.
+#[ic_cdk::query]
+fn get_canister_stats() -> CanisterStats {
+    CanisterStats {
+        total_expenses: STORAGE.with(|service| service.borrow().len()),
+        total_categories: CATEGORY_STORAGE.with(|service| service.borrow().len()),
+        next_id: ID_COUNTER.with(|counter| *counter.borrow().get()),
+        estimated_bytes_used: ic_cdk::api::stable::stable64_size() * WASM_PAGE_SIZE,
+        cycle_balance: ic_cdk::api::canister_balance(),
+    }
+}
+
+// How many expenses the caller has stored, archived ones included, which is
+// what the `max_expenses_per_owner` quota is checked against
+#[ic_cdk::query]
+fn get_my_expense_count() -> u64 {
+    owner_expense_count(&caller())
+}
+
+// Counts the caller's non-archived expenses without collecting them
+#[ic_cdk::query]
+fn count_expenses() -> u64 {
+    let owner = caller();
+    STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, expense)| expense.owner == owner && !expense.archived)
+            .count() as u64
+    })
+}
+
+// Reports whether an expense with this id exists and belongs to the caller,
+// archived or not
+#[ic_cdk::query]
+fn expense_exists(id: u64) -> bool {
+    _get_expense(&id).is_some_and(|expense| expense.owner == caller())
+}
+
+#[ic_cdk::query]
+fn list_archived_expenses() -> Vec<Expense> {
+    newest_first(
+        _get_owned_expenses(&caller())
+            .into_iter()
+            .filter(|expense| expense.archived)
+            .collect(),
+    )
+}
+
+#[ic_cdk::query]
+fn get_expenses_by_category(category_id: u64) -> Vec<Expense> {
+    newest_first(
+        _get_active_expenses(&caller())
+            .into_iter()
+            .filter(|expense| expense.category_id == Some(category_id))
+            .collect(),
+    )
+}
+
+// The caller's merchant names, lowercased and deduplicated, in alphabetical
+// order. At most `MAX_DISTINCT_MERCHANTS` are returned.
+#[ic_cdk::query]
+fn list_distinct_merchants() -> Vec<String> {
+    let merchants: BTreeSet<String> = _get_active_expenses(&caller())
+        .into_iter()
+        .filter_map(|expense| expense.merchant)
+        .map(|merchant| merchant.trim().to_lowercase())
+        .collect();
+    merchants.into_iter().take(MAX_DISTINCT_MERCHANTS).collect()
+}
+
+#[ic_cdk::query]
+fn get_expenses_by_merchant(name: String) -> Vec<Expense> {
+    let name = name.trim().to_lowercase();
+    newest_first(
+        _get_active_expenses(&caller())
+            .into_iter()
+            .filter(|expense| {
+                expense
+                    .merchant
+                    .as_ref()
+                    .is_some_and(|merchant| merchant.trim().to_lowercase() == name)
+            })
+            .collect(),
+    )
+}
+
+#[ic_cdk::query]
+fn get_expenses_by_tag(tag: String) -> Vec<Expense> {
+    let tag = tag.trim().to_lowercase();
+    newest_first(
+        _get_active_expenses(&caller())
+            .into_iter()
+            .filter(|expense| expense.tags.contains(&tag))
+            .collect(),
+    )
+}
+
+// Sums a participant's shares across the caller's expenses, ignoring case
+#[ic_cdk::query]
+fn get_owed_by_participant(name: String) -> f64 {
+    let name = name.trim().to_lowercase();
+    let owed: i64 = _get_active_expenses(&caller())
+        .iter()
+        .filter(|expense| is_spending(expense))
+        .flat_map(|expense| expense.splits.iter())
+        .filter(|split| split.participant.to_lowercase() == name)
+        .map(|split| split.share)
+        .sum();
+    from_minor_units(owed)
+}
+
+#[ic_cdk::query]
+fn get_expenses_with_receipts() -> Vec<Expense> {
+    newest_first(
+        _get_active_expenses(&caller())
+            .into_iter()
+            .filter(|expense| expense.receipt_url.is_some())
+            .collect(),
+    )
+}
+
+#[ic_cdk::query]
+fn get_expenses_without_receipts() -> Vec<Expense> {
+    newest_first(
+        _get_active_expenses(&caller())
+            .into_iter()
+            .filter(|expense| expense.receipt_url.is_none())
+            .collect(),
+    )
+}
+
+#[ic_cdk::query]
+fn query_expenses(filter: ExpenseFilter) -> Vec<Expense> {
+    newest_first(
+        _get_active_expenses(&caller())
+            .into_iter()
+            .filter(|expense| filter.matches(expense))
+            .collect(),
+    )
+}
+
+// Expenses recorded within the inclusive range, by `created_at` rather than
+// the expense date, in the order they were recorded
+#[ic_cdk::query]
+fn get_expenses_created_between(start: u64, end: u64) -> Vec<Expense> {
+    let mut expenses: Vec<Expense> = _get_active_expenses(&caller())
+        .into_iter()
+        .filter(|expense| expense.created_at >= start && expense.created_at <= end)
+        .collect();
+    expenses.sort_by_key(|expense| (expense.created_at, expense.id));
+    expenses
+}
+
+// Expenses last updated within the inclusive range, in update order.
+// Expenses that were never updated are left out.
+#[ic_cdk::query]
+fn get_expenses_updated_between(start: u64, end: u64) -> Vec<Expense> {
+    let mut expenses: Vec<Expense> = _get_active_expenses(&caller())
+        .into_iter()
+        .filter(|expense| {
+            expense
+                .updated_at
+                .is_some_and(|updated_at| updated_at >= start && updated_at <= end)
+        })
+        .collect();
+    expenses.sort_by_key(|expense| (expense.updated_at, expense.id));
+    expenses
+}
+
+// Expenses dated within the last `days` days, newest first
+#[ic_cdk::query]
+fn get_recent_expenses(days: u64) -> Result<Vec<Expense>, Error> {
+    if !(1..=MAX_RECENT_DAYS).contains(&days) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "days must be between 1 and {}, got {}",
+                MAX_RECENT_DAYS, days
+            ),
+        });
+    }
+    let cutoff = time().saturating_sub(days * NANOS_PER_DAY);
+    Ok(newest_first(
+        _get_active_expenses(&caller())
+            .into_iter()
+            .filter(|expense| expense.date >= cutoff)
+            .collect(),
+    ))
+}
+
+// Expenses strictly larger than `min_amount`, smallest first
+#[ic_cdk::query]
+fn get_expenses_above_amount(min_amount: f64) -> Vec<Expense> {
+    let mut expenses: Vec<Expense> = _get_active_expenses(&caller())
+        .into_iter()
+        .filter(|expense| from_minor_units(expense.amount) > min_amount)
+        .collect();
+    expenses.sort_by_key(|expense| (expense.amount, expense.id));
+    expenses
+}
+
+// Expenses strictly smaller than `max_amount`, smallest first
+#[ic_cdk::query]
+fn get_expenses_below_amount(max_amount: f64) -> Vec<Expense> {
+    let mut expenses: Vec<Expense> = _get_active_expenses(&caller())
+        .into_iter()
+        .filter(|expense| from_minor_units(expense.amount) < max_amount)
+        .collect();
+    expenses.sort_by_key(|expense| (expense.amount, expense.id));
+    expenses
+}
+
+// Expenses between `min_amount` and `max_amount` inclusive, smallest first
+#[ic_cdk::query]
+fn get_expenses_in_amount_range(min_amount: f64, max_amount: f64) -> Result<Vec<Expense>, Error> {
+    if min_amount.is_nan() || max_amount.is_nan() || min_amount > max_amount {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "min_amount must not be greater than max_amount, got {} and {}",
+                min_amount, max_amount
+            ),
+        });
+    }
+    let mut expenses: Vec<Expense> = _get_active_expenses(&caller())
+        .into_iter()
+        .filter(|expense| {
+            let amount = from_minor_units(expense.amount);
+            amount >= min_amount && amount <= max_amount
+        })
+        .collect();
+    expenses.sort_by_key(|expense| (expense.amount, expense.id));
+    Ok(expenses)
+}
+
+// Expenses whose amount is within `tolerance` of `target`, closest first.
+// Useful for matching statement lines that differ from the logged amount by
+// rounding. Distances are compared in cents, so the ordering is exact.
+#[ic_cdk::query]
+fn find_expenses_near_amount(target: f64, tolerance: f64) -> Result<Vec<Expense>, Error> {
+    let target = to_minor_units(target).map_err(|error| error.prefixed("target"))?;
+    let tolerance = to_minor_units(tolerance).map_err(|error| error.prefixed("tolerance"))?;
+    if tolerance < 0 {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "tolerance must not be negative, got {}",
+                from_minor_units(tolerance)
+            ),
+        });
+    }
+    let mut expenses: Vec<Expense> = _get_active_expenses(&caller())
+        .into_iter()
+        .filter(|expense| expense.amount.abs_diff(target) <= tolerance as u64)
+        .collect();
+    expenses.sort_by_key(|expense| (expense.amount.abs_diff(target), expense.id));
+    Ok(expenses)
+}
+
+// Pages through the caller's expenses in id order, starting after
+// `cursor_id`. Unlike offset pagination, pages stay consistent when
+// expenses are added or removed between calls.
+#[ic_cdk::query]
+fn get_expenses_after(cursor_id: Option<u64>, limit: usize) -> Result<ExpensePage, Error> {
+    if !(1..=MAX_PER_PAGE).contains(&limit) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "limit must be between 1 and {}, got {}",
+                MAX_PER_PAGE, limit
+            ),
+        });
+    }
+    let owner = caller();
+    let start = cursor_id.map_or(Bound::Unbounded, Bound::Excluded);
+    let mut items: Vec<Expense> = STORAGE.with(|service| {
+        service
+            .borrow()
+            .range((start, Bound::Unbounded))
+            .map(|(_, expense)| expense)
+            .filter(|expense| expense.owner == owner && !expense.archived)
+            .take(limit + 1)
+            .collect()
+    });
+    // One extra item was fetched to tell whether another page follows
+    let next_cursor = if items.len() > limit {
+        items.truncate(limit);
+        items.last().map(|expense| expense.id)
+    } else {
+        None
+    };
+    Ok(ExpensePage { items, next_cursor })
+}
+
+// `per_page` falls back to the `default_per_page` setting when omitted
+#[ic_cdk::query]
+fn get_paginated_expenses(
+    page: usize,
+    per_page: Option<usize>,
+) -> Result<PaginatedExpenses, Error> {
+    paginate(
+        newest_first(_get_active_expenses(&caller())),
+        page,
+        per_page,
+    )
+}
+
+// Applies `filter` and returns one page of the matching expenses, with
+// `total` counting every match rather than just this page
+#[ic_cdk::query]
+fn query_expenses_paginated(
+    filter: ExpenseFilter,
+    page: usize,
+    per_page: Option<usize>,
+) -> Result<PaginatedExpenses, Error> {
+    paginate(query_expenses(filter), page, per_page)
+}
+
+// Helper function to cut one page out of an already ordered list. `per_page`
+// falls back to the `default_per_page` setting and is capped at `MAX_PER_PAGE`.
+fn paginate(
+    expenses: Vec<Expense>,
+    page: usize,
+    per_page: Option<usize>,
+) -> Result<PaginatedExpenses, Error> {
+    let per_page = per_page.unwrap_or_else(|| current_settings().default_per_page);
+    if page < 1 || per_page < 1 {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "page and per_page must be at least 1, got page={} and per_page={}",
+                page, per_page
+            ),
+        });
+    }
+    let per_page = per_page.min(MAX_PER_PAGE);
+    let total = expenses.len();
+    let total_pages = total.div_ceil(per_page);
+    let items = expenses
+        .into_iter()
+        .skip((page - 1).saturating_mul(per_page))
+        .take(per_page)
+        .collect();
+    Ok(PaginatedExpenses {
+        items,
+        total: total as u64,
+        page,
+        per_page,
+        total_pages,
+    })
+}
+
+#[ic_cdk::query]
+fn search_expenses(query: String) -> Result<Vec<Expense>, Error> {
+    let query = query.trim().to_lowercase();
+    if query.is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "Search query must not be empty".to_string(),
+        });
+    }
+    Ok(newest_first(
+        _get_active_expenses(&caller())
+            .into_iter()
+            .filter(|expense| expense.description.trim().to_lowercase().contains(&query))
+            .collect(),
+    ))
+}
+
+// Expenses whose whole description equals `description`, ignoring case and
+// surrounding whitespace, newest first. Unlike `search_expenses`, a
+// description that only contains it does not match.
+#[ic_cdk::query]
+fn get_expenses_by_exact_description(description: String) -> Vec<Expense> {
+    let description = description.trim().to_lowercase();
+    newest_first(
+        _get_active_expenses(&caller())
+            .into_iter()
+            .filter(|expense| expense.description.trim().to_lowercase() == description)
+            .collect(),
+    )
+}
+
+// Autocomplete suggestions: the most recent expense for each distinct
+// description starting with `description_prefix`, ignoring case, newest
+// first, so that a client can offer to repeat its amount and category
+#[ic_cdk::query]
+fn suggest_similar(description_prefix: String, limit: usize) -> Result<Vec<Expense>, Error> {
+    let prefix = description_prefix.trim().to_lowercase();
+    if prefix.is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "Description prefix must not be empty".to_string(),
+        });
+    }
+    if !(1..=MAX_SUGGESTIONS).contains(&limit) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "limit must be between 1 and {}, got {}",
+                MAX_SUGGESTIONS, limit
+            ),
+        });
+    }
+    let mut seen = BTreeSet::new();
+    Ok(newest_first(
+        _get_active_expenses(&caller())
+            .into_iter()
+            .filter(|expense| expense.description.to_lowercase().starts_with(&prefix))
+            .collect(),
+    )
+    .into_iter()
+    .filter(|expense| seen.insert(expense.description.to_lowercase()))
+    .take(limit)
+    .collect())
+}
+
+// Total approved spending, net of refunds
+#[ic_cdk::query]
+fn calculate_total_expenses() -> f64 {
+    let total: i64 = _get_active_expenses(&caller())
+        .iter()
+        .filter(|expense| is_spending(expense))
+        .map(net_amount)
+        .sum();
+    from_minor_units(total)
+}
+
+// Same total as `calculate_total_expenses`, formatted with exactly two
+// decimal places (e.g. "30.00") for display
+#[ic_cdk::query]
+fn calculate_total_expenses_formatted() -> String {
+    let total: i64 = _get_active_expenses(&caller())
+        .iter()
+        .filter(|expense| is_spending(expense))
+        .map(net_amount)
+        .sum();
+    format_minor_units(total)
+}
+
+// Every approved entry in date order, annotated with the cumulative total
+// spent so far. Expenses raise the running total and income lowers it.
+#[ic_cdk::query]
+fn get_balance_timeline() -> Vec<BalanceEntry> {
+    let mut entries: Vec<Expense> = _get_active_expenses(&caller())
+        .into_iter()
+        .filter(is_approved)
+        .collect();
+    entries.sort_by_key(|entry| (entry.date, entry.id));
+    let mut running_total: i64 = 0;
+    entries
+        .into_iter()
+        .map(|entry| {
+            running_total += match entry.entry_type {
+                EntryType::Expense => entry.amount,
+                EntryType::Income => -entry.amount,
+            };
+            BalanceEntry {
+                expense_id: entry.id,
+                date: entry.date,
+                amount: from_minor_units(entry.amount),
+                entry_type: entry.entry_type,
+                running_total: from_minor_units(running_total),
+            }
+        })
+        .collect()
+}
+
+// Total income minus total expenses, net of refunds
+#[ic_cdk::query]
+fn calculate_net_balance() -> f64 {
+    let balance: i64 = _get_active_expenses(&caller())
+        .iter()
+        .filter(|entry| is_approved(entry))
+        .map(|entry| match entry.entry_type {
+            EntryType::Income => entry.amount,
+            EntryType::Expense => -net_amount(entry),
+        })
+        .sum();
+    from_minor_units(balance)
+}
+
+// Like `calculate_total_expenses`, but lets callers tell "no expenses" apart
+// from "expenses that sum to zero"
+#[ic_cdk::query]
+fn calculate_total_expenses_detailed() -> TotalSummary {
+    let expenses: Vec<Expense> = _get_active_expenses(&caller())
+        .into_iter()
+        .filter(is_spending)
+        .collect();
+    let total: i64 = expenses.iter().map(net_amount).sum();
+    TotalSummary {
+        total: from_minor_units(total),
+        count: expenses.len() as u64,
+        is_empty: expenses.is_empty(),
+    }
+}
+
+// Sums spending per payment method; expenses without a method are left out
+#[ic_cdk::query]
+fn get_totals_by_payment_method() -> Vec<(PaymentMethod, f64)> {
+    let mut totals: BTreeMap<PaymentMethod, i64> = BTreeMap::new();
+    for expense in _get_active_expenses(&caller())
+        .into_iter()
+        .filter(is_spending)
+    {
+        if let Some(method) = expense.payment_method {
+            *totals.entry(method).or_insert(0) += net_amount(&expense);
+        }
+    }
+    totals
+        .into_iter()
+        .map(|(method, total)| (method, from_minor_units(total)))
+        .collect()
+}
+
+// Sum of amounts per currency code as recorded, net of refunds and without
+// conversion, largest first. Records stored before expenses had a currency count towards the
+// base currency.
+#[ic_cdk::query]
+fn get_totals_by_currency() -> Vec<(String, f64)> {
+    let base_currency = current_settings().base_currency;
+    let mut totals: BTreeMap<String, i64> = BTreeMap::new();
+    for expense in _get_active_expenses(&caller())
+        .into_iter()
+        .filter(is_spending)
+    {
+        let amount = net_amount(&expense);
+        let currency = if expense.currency.is_empty() {
+            base_currency.clone()
+        } else {
+            expense.currency
+        };
+        *totals.entry(currency).or_insert(0) += amount;
+    }
+    let mut totals: Vec<(String, i64)> = totals.into_iter().collect();
+    totals.sort_by_key(|&(_, total)| Reverse(total));
+    totals
+        .into_iter()
+        .map(|(currency, total)| (currency, from_minor_units(total)))
+        .collect()
+}
+
+// Total converted into the base currency with the stored exchange rates.
+// Records stored before expenses had a currency are taken to be in the base
+// currency, as in `get_totals_by_currency`.
+#[ic_cdk::query]
+fn calculate_total_in_base() -> Result<f64, Error> {
+    let base_currency = current_settings().base_currency;
+    let mut total = 0.0;
+    for expense in _get_active_expenses(&caller())
+        .into_iter()
+        .filter(is_spending)
+    {
+        if expense.currency.is_empty() || expense.currency == base_currency {
+            total += from_minor_units(net_amount(&expense));
+            continue;
+        }
+        let code = CurrencyCode(expense.currency.clone());
+        match EXCHANGE_RATE_STORAGE.with(|service| service.borrow().get(&code)) {
+            Some(rate) => total += from_minor_units(net_amount(&expense)) * rate,
+            None => {
+                return Err(Error::InvalidInput {
+                    msg: format!(
+                        "No exchange rate set for currency {} used by expense with id={}",
+                        expense.currency, expense.id
+                    ),
+                })
+            }
+        }
+    }
+    Ok(total)
+}
+
+#[ic_cdk::query]
+fn get_expense_statistics() -> ExpenseStats {
+    let mut amounts: Vec<i64> = _get_active_expenses(&caller())
+        .iter()
+        .filter(|expense| is_spending(expense))
+        .map(net_amount)
+        .collect();
+    if amounts.is_empty() {
+        return ExpenseStats::default();
+    }
+    amounts.sort_unstable();
+
+    let count = amounts.len();
+    let total: i64 = amounts.iter().sum();
+    let middle = count / 2;
+    let median = if count.is_multiple_of(2) {
+        (from_minor_units(amounts[middle - 1]) + from_minor_units(amounts[middle])) / 2.0
+    } else {
+        from_minor_units(amounts[middle])
+    };
+    ExpenseStats {
+        count: count as u64,
+        total: from_minor_units(total),
+        average: from_minor_units(total) / count as f64,
+        min: from_minor_units(amounts[0]),
+        max: from_minor_units(amounts[count - 1]),
+        median,
+    }
+}
+
+// The `p`-th percentile (0 to 100) of the amounts used by
+// `get_expense_statistics`, interpolating linearly between neighbouring ranks
+#[ic_cdk::query]
+fn get_amount_percentile(p: f64) -> Result<f64, Error> {
+    if !(0.0..=100.0).contains(&p) {
+        return Err(Error::InvalidInput {
+            msg: format!("Percentile must be between 0 and 100, got {}", p),
+        });
+    }
+    Ok(percentile(&sorted_amounts()?, p))
+}
+
+#[ic_cdk::query]
+fn get_quartiles() -> Result<Quartiles, Error> {
+    let amounts = sorted_amounts()?;
+    Ok(Quartiles {
+        q1: percentile(&amounts, 25.0),
+        median: percentile(&amounts, 50.0),
+        q3: percentile(&amounts, 75.0),
+    })
+}
+
+// Expenses, excluding income, counted and totalled in `bucket_size`-wide
+// amount ranges starting at zero, smallest first. Empty ranges are kept up
+// to the largest expense so a chart has no gaps, and none follow it. At most
+// `MAX_HISTOGRAM_BUCKETS` buckets are returned; a smaller `bucket_size` is
+// rejected rather than truncated.
+#[ic_cdk::query]
+fn get_amount_histogram(bucket_size: f64) -> Result<Vec<HistogramBucket>, Error> {
+    if !bucket_size.is_finite() || bucket_size <= 0.0 {
+        return Err(Error::InvalidInput {
+            msg: format!("bucket_size must be a positive number, got {}", bucket_size),
+        });
+    }
+    let size = to_minor_units(bucket_size)?;
+    if size == 0 {
+        return Err(Error::InvalidInput {
+            msg: "bucket_size must be at least 0.01".to_string(),
+        });
+    }
+    let amounts: Vec<i64> = _get_active_expenses(&caller())
+        .iter()
+        .filter(|expense| is_spending(expense))
+        .map(|expense| expense.amount)
+        .collect();
+    let Some(&largest) = amounts.iter().max() else {
+        return Ok(Vec::new());
+    };
+    let bucket_count = (largest / size) as u64 + 1;
+    if bucket_count > MAX_HISTOGRAM_BUCKETS {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "bucket_size {} would need {} buckets, but at most {} are allowed",
+                bucket_size, bucket_count, MAX_HISTOGRAM_BUCKETS
+            ),
+        });
+    }
+    let mut buckets = vec![(0u64, 0i64); bucket_count as usize];
+    for amount in amounts {
+        let bucket = &mut buckets[(amount / size) as usize];
+        bucket.0 += 1;
+        bucket.1 += amount;
+    }
+    Ok(buckets
+        .into_iter()
+        .zip(0i64..)
+        .map(|((count, total), index)| HistogramBucket {
+            range_start: from_minor_units(index * size),
+            range_end: from_minor_units((index + 1) * size),
+            count,
+            total: from_minor_units(total),
+        })
+        .collect())
+}
+
+// Total, count, average, extremes, top category, and per-day totals of the
+// caller's spending within the inclusive date range, from a single scan of
+// the store. Income is left out; an empty range gives all zeros.
+#[ic_cdk::query]
+fn get_period_summary(start_date: u64, end_date: u64) -> Result<PeriodSummary, Error> {
+    if start_date > end_date {
+        return Err(Error::InvalidInput {
+            msg: "start_date must not be after end_date".to_string(),
+        });
+    }
+    let owner = caller();
+    let expenses: Vec<Expense> = STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, expense)| expense)
+            .filter(|expense| {
+                expense.owner == owner
+                    && !expense.archived
+                    && is_spending(expense)
+                    && expense.date >= start_date
+                    && expense.date <= end_date
+            })
+            .collect()
+    });
+    let (Some(min), Some(max)) = (
+        expenses.iter().map(net_amount).min(),
+        expenses.iter().map(net_amount).max(),
+    ) else {
+        return Ok(PeriodSummary::default());
+    };
+
+    let count = expenses.len() as u64;
+    let total: i64 = expenses.iter().map(net_amount).sum();
+    let top_category = totals_by_category(expenses.iter().cloned())
+        .into_iter()
+        .min_by_key(|&(category_id, (category_total, _))| (Reverse(category_total), category_id))
+        .map(|(category_id, (category_total, _))| CategoryShare {
+            category_id,
+            category_name: category_display_name(category_id),
+            total: from_minor_units(category_total),
+            percentage: (category_total as f64 * 10_000.0 / total as f64).round() / 100.0,
+        });
+    Ok(PeriodSummary {
+        total: from_minor_units(total),
+        count,
+        average: from_minor_units(total) / count as f64,
+        min: from_minor_units(min),
+        max: from_minor_units(max),
+        top_category,
+        daily_totals: totals_by_day(expenses.into_iter(), 0),
+    })
+}
+
+// Exports the caller's expenses as RFC 4180 CSV, ordered by date. The whole
+// file is returned in one reply, so it is subject to the IC's 2 MiB response
+// limit; very large ledgers should be fetched with `get_paginated_expenses`.
+#[ic_cdk::query]
+fn export_expenses_csv() -> String {
+    let mut expenses = _get_active_expenses(&caller());
+    expenses.sort_by_key(|expense| (expense.date, expense.id));
+
+    let mut csv = String::from("id,description,amount,date,created_at,updated_at\r\n");
+    for expense in expenses {
+        csv.push_str(&format!(
+            "{},{},{},{},{},{}\r\n",
+            expense.id,
+            escape_csv_field(&expense.description),
+            format_minor_units(expense.amount),
+            expense.date,
+            expense.created_at,
+            expense
+                .updated_at
+                .map_or(String::new(), |updated_at| updated_at.to_string()),
+        ));
+    }
+    csv
+}
+
+// Average spend per calendar day (UTC) over the inclusive range, counting
+// days without expenses
+#[ic_cdk::query]
+fn get_average_daily_spend(start_date: u64, end_date: u64) -> Result<f64, Error> {
+    let (total, _) = sum_spending_in_range(&_get_active_expenses(&caller()), start_date, end_date)?;
+    let days = end_date / NANOS_PER_DAY - start_date / NANOS_PER_DAY + 1;
+    Ok(from_minor_units(total) / days as f64)
+}
+
+// Compares spending in period A against period B, e.g. this month against
+// last month. Both ranges are inclusive.
+#[ic_cdk::query]
+fn compare_periods(
+    period_a_start: u64,
+    period_a_end: u64,
+    period_b_start: u64,
+    period_b_end: u64,
+) -> Result<PeriodComparison, Error> {
+    let expenses = _get_active_expenses(&caller());
+    let (total_a, _) = sum_spending_in_range(&expenses, period_a_start, period_a_end)
+        .map_err(|error| error.prefixed("Period A"))?;
+    let (total_b, _) = sum_spending_in_range(&expenses, period_b_start, period_b_end)
+        .map_err(|error| error.prefixed("Period B"))?;
+    let difference = total_a - total_b;
+    Ok(PeriodComparison {
+        period_a_total: from_minor_units(total_a),
+        period_b_total: from_minor_units(total_b),
+        difference: from_minor_units(difference),
+        percentage_change: if total_b == 0 {
+            None
+        } else {
+            Some((difference as f64 * 10_000.0 / total_b as f64).round() / 100.0)
+        },
+    })
+}
+
+// Average spend per calendar month over the span from the earliest to the
+// latest expense, counting months without expenses
+#[ic_cdk::query]
+fn get_average_monthly_spend() -> f64 {
+    let expenses: Vec<Expense> = _get_active_expenses(&caller())
+        .into_iter()
+        .filter(is_spending)
+        .collect();
+    let (Some(earliest), Some(latest)) = (
+        expenses.iter().map(|expense| expense.date).min(),
+        expenses.iter().map(|expense| expense.date).max(),
+    ) else {
+        return 0.0;
+    };
+    let (first_year, first_month, _) = civil_date_from_nanos(earliest);
+    let (last_year, last_month, _) = civil_date_from_nanos(latest);
+    let months = (last_year * 12 + last_month) - (first_year * 12 + first_month) + 1;
+    let total: i64 = expenses.iter().map(net_amount).sum();
+    from_minor_units(total) / months as f64
+}
+
+// Months are calendar months in `utc_offset_minutes` (UTC when omitted), so
+// late-night expenses land in the month they were made locally
+#[ic_cdk::query]
+fn get_monthly_totals(utc_offset_minutes: Option<i32>) -> Result<Vec<MonthlyTotal>, Error> {
+    let offset = validate_utc_offset(utc_offset_minutes)?;
+    let mut months: BTreeMap<(u32, u32), (i64, u64)> = BTreeMap::new();
+    for expense in _get_active_expenses(&caller())
+        .into_iter()
+        .filter(is_spending)
+    {
+        let (year, month, _) = civil_date_from_nanos(shift_to_offset(expense.date, offset));
+        let entry = months.entry((year, month)).or_insert((0, 0));
+        entry.0 += net_amount(&expense);
+        entry.1 += 1;
+    }
+    Ok(months
+        .into_iter()
+        .map(|((year, month), (total, count))| MonthlyTotal {
+            year,
+            month,
+            total: from_minor_units(total),
+            count,
+        })
+        .collect())
+}
+
+// Totals for `num_weeks` consecutive 7-day windows, the first starting at
+// `start_date`
+#[ic_cdk::query]
+fn get_weekly_totals(start_date: u64, num_weeks: u32) -> Result<Vec<WeeklyTotal>, Error> {
+    if !(1..=MAX_WEEKS).contains(&num_weeks) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "num_weeks must be between 1 and {}, got {}",
+                MAX_WEEKS, num_weeks
+            ),
+        });
+    }
+    let expenses = _get_active_expenses(&caller());
+    let mut weeks = Vec::with_capacity(num_weeks as usize);
+    for week in 0..num_weeks as u64 {
+        let week_start = start_date.checked_add(week * 7 * NANOS_PER_DAY);
+        let week_end = week_start.and_then(|start| start.checked_add(7 * NANOS_PER_DAY - 1));
+        let (Some(week_start), Some(week_end)) = (week_start, week_end) else {
+            return Err(Error::InvalidInput {
+                msg: "The requested weeks extend past the largest representable date".to_string(),
+            });
+        };
+        let (total, count) = sum_spending_in_range(&expenses, week_start, week_end)?;
+        weeks.push(WeeklyTotal {
+            week_start,
+            week_end,
+            total: from_minor_units(total),
+            count,
+        });
+    }
+    Ok(weeks)
+}
+
+// Days are calendar days in `utc_offset_minutes` (UTC when omitted)
+#[ic_cdk::query]
+fn get_daily_totals(
+    year: u32,
+    month: u32,
+    utc_offset_minutes: Option<i32>,
+) -> Result<Vec<DailyTotal>, Error> {
+    if !(1..=12).contains(&month) {
+        return Err(Error::InvalidInput {
+            msg: format!("Month must be between 1 and 12, got {}", month),
+        });
+    }
+    let offset = validate_utc_offset(utc_offset_minutes)?;
+    Ok(totals_by_day(
+        _get_active_expenses(&caller())
+            .into_iter()
+            .filter(|expense| {
+                let (expense_year, expense_month, _) =
+                    civil_date_from_nanos(shift_to_offset(expense.date, offset));
+                expense_year == year && expense_month == month
+            }),
+        offset,
+    ))
+}
+
+// Spending, excluding income, for each day of the week, Monday first. All
+// seven weekdays are returned, with zeros for days without expenses.
+#[ic_cdk::query]
+fn get_spending_by_weekday() -> Vec<WeekdayTotal> {
+    let mut weekdays = [(0i64, 0u64); 7];
+    for expense in _get_active_expenses(&caller())
+        .into_iter()
+        .filter(is_spending)
+    {
+        let entry = &mut weekdays[weekday_from_nanos(expense.date) as usize];
+        entry.0 += net_amount(&expense);
+        entry.1 += 1;
+    }
+    weekdays
+        .into_iter()
+        .zip(0u8..)
+        .map(|((total, count), weekday)| WeekdayTotal {
+            weekday,
+            total: from_minor_units(total),
+            count,
+        })
+        .collect()
+}
+
+// Expenses and income dated within a UTC calendar year, oldest first, so
+// clients don't have to work out the year's boundaries themselves
+#[ic_cdk::query]
+fn get_expenses_by_year(year: u32) -> Result<Vec<Expense>, Error> {
+    expenses_in_year(year)
+}
+
+// Same total as `calculate_total_expenses`, for a single UTC calendar year
+#[ic_cdk::query]
+fn get_year_total(year: u32) -> Result<f64, Error> {
+    let total: i64 = expenses_in_year(year)?
+        .iter()
+        .filter(|expense| is_spending(expense))
+        .map(net_amount)
+        .sum();
+    Ok(from_minor_units(total))
+}
+
+// Fits a least-squares line through the daily spending totals in the
+// inclusive range, using only days that have expenses. A slope within
+// `TREND_FLAT_THRESHOLD` of zero, or fewer than two such days, is `Flat`.
+#[ic_cdk::query]
+fn get_spending_trend(start_date: u64, end_date: u64) -> Result<TrendResult, Error> {
+    if start_date > end_date {
+        return Err(Error::InvalidInput {
+            msg: "start_date must not be after end_date".to_string(),
+        });
+    }
+    let daily_points = totals_by_day(
+        _get_active_expenses(&caller())
+            .into_iter()
+            .filter(|expense| expense.date >= start_date && expense.date <= end_date),
+        0,
+    );
+    let slope = if daily_points.len() < 2 {
+        0.0
+    } else {
+        let first_day = daily_points[0].date / NANOS_PER_DAY;
+        let points: Vec<(f64, f64)> = daily_points
+            .iter()
+            .map(|point| ((point.date / NANOS_PER_DAY - first_day) as f64, point.total))
+            .collect();
+        let n = points.len() as f64;
+        let mean_x = points.iter().map(|&(x, _)| x).sum::<f64>() / n;
+        let mean_y = points.iter().map(|&(_, y)| y).sum::<f64>() / n;
+        let covariance: f64 = points
+            .iter()
+            .map(|&(x, y)| (x - mean_x) * (y - mean_y))
+            .sum();
+        let variance: f64 = points.iter().map(|&(x, _)| (x - mean_x).powi(2)).sum();
+        covariance / variance
+    };
+    let direction = if slope > TREND_FLAT_THRESHOLD {
+        TrendDirection::Increasing
+    } else if slope < -TREND_FLAT_THRESHOLD {
+        TrendDirection::Decreasing
+    } else {
+        TrendDirection::Flat
+    };
+    Ok(TrendResult {
+        slope,
+        direction,
+        daily_points,
+    })
+}
+
+// Share of spending per category within the inclusive date range, largest
+// first. Percentages are rounded to two decimals, so they may not add up to
+// exactly 100.
+#[ic_cdk::query]
+fn get_category_breakdown(start_date: u64, end_date: u64) -> Vec<CategoryShare> {
+    let totals = totals_by_category(
+        _get_active_expenses(&caller())
+            .into_iter()
+            .filter(|expense| expense.date >= start_date && expense.date <= end_date),
+    );
+    let grand_total: i64 = totals.values().map(|&(total, _)| total).sum();
+    let mut shares: Vec<(Option<u64>, i64)> = totals
+        .into_iter()
+        .map(|(category_id, (total, _))| (category_id, total))
+        .collect();
+    shares.sort_by_key(|&(category_id, total)| (Reverse(total), category_id));
+    shares
+        .into_iter()
+        .map(|(category_id, total)| CategoryShare {
+            category_id,
+            category_name: category_display_name(category_id),
+            total: from_minor_units(total),
+            percentage: if grand_total == 0 {
+                0.0
+            } else {
+                (total as f64 * 10_000.0 / grand_total as f64).round() / 100.0
+            },
+        })
+        .collect()
+}
+
+// Number of expenses per category id, most first. Uncategorized expenses are
+// counted under `UNCATEGORIZED_ID`.
+#[ic_cdk::query]
+fn count_expenses_by_category() -> Vec<(u64, u64)> {
+    let mut counts: Vec<(u64, u64)> =
+        totals_by_category(_get_active_expenses(&caller()).into_iter())
+            .into_iter()
+            .map(|(category_id, (_, count))| (category_id.unwrap_or(UNCATEGORIZED_ID), count))
+            .collect();
+    counts.sort_by_key(|&(category_id, count)| (Reverse(count), category_id));
+    counts
+}
+
+// The largest expense in each category that has any, by category id, from a
+// single pass over the store. Uncategorized expenses are listed under
+// `UNCATEGORIZED_ID`, income is left out, and a split expense competes in
+// each of its categories with the part that belongs there. Ties go to the
+// lowest id.
+#[ic_cdk::query]
+fn get_max_expense_per_category() -> Vec<(u64, Expense)> {
+    let owner = caller();
+    let mut largest: BTreeMap<u64, (i64, Expense)> = BTreeMap::new();
+    STORAGE.with(|service| {
+        for (_, expense) in service.borrow().iter() {
+            if expense.owner != owner || expense.archived || !is_spending(&expense) {
+                continue;
+            }
+            for (category_id, amount) in category_parts(&expense) {
+                let category_id = category_id.unwrap_or(UNCATEGORIZED_ID);
+                if largest
+                    .get(&category_id)
+                    .is_none_or(|&(current, _)| amount > current)
+                {
+                    largest.insert(category_id, (amount, expense.clone()));
+                }
+            }
+        }
+    });
+    largest
+        .into_iter()
+        .map(|(category_id, (_, expense))| (category_id, expense))
+        .collect()
+}
+
+// Expenses whose amount is more than `z_threshold` standard deviations above
+// the mean of their category, newest first. Uncategorized expenses form a
+// group of their own, income is left out, and groups with fewer than
+// `MIN_ANOMALY_SAMPLE` expenses are skipped.
+#[ic_cdk::query]
+fn get_anomalous_expenses(z_threshold: f64) -> Result<Vec<Expense>, Error> {
+    if !z_threshold.is_finite() || z_threshold <= 0.0 {
+        return Err(Error::InvalidInput {
+            msg: format!("z_threshold must be a positive number, got {}", z_threshold),
+        });
+    }
+    let mut groups: BTreeMap<Option<u64>, Vec<Expense>> = BTreeMap::new();
+    for expense in _get_active_expenses(&caller())
+        .into_iter()
+        .filter(is_spending)
+    {
+        groups.entry(expense.category_id).or_default().push(expense);
+    }
+    let mut anomalies = Vec::new();
+    for expenses in groups.into_values() {
+        if expenses.len() < MIN_ANOMALY_SAMPLE {
+            continue;
+        }
+        let count = expenses.len() as f64;
+        let mean = expenses
+            .iter()
+            .map(|expense| expense.amount as f64)
+            .sum::<f64>()
+            / count;
+        let variance = expenses
+            .iter()
+            .map(|expense| (expense.amount as f64 - mean).powi(2))
+            .sum::<f64>()
+            / count;
+        let cutoff = mean + z_threshold * variance.sqrt();
+        anomalies.extend(
+            expenses
+                .into_iter()
+                .filter(|expense| expense.amount as f64 > cutoff),
+        );
+    }
+    Ok(newest_first(anomalies))
+}
+
+// Mean expense amount per category, ordered by category id. Categories
+// without expenses are left out.
+#[ic_cdk::query]
+fn get_category_averages() -> Vec<CategoryAverage> {
+    totals_by_category(_get_active_expenses(&caller()).into_iter())
+        .into_iter()
+        .map(|(category_id, (total, count))| CategoryAverage {
+            category_id,
+            category_name: category_display_name(category_id),
+            average: from_minor_units(total) / count as f64,
+            count,
+        })
+        .collect()
+}
+
+#[ic_cdk::query]
+fn get_expenses_sorted(field: SortField, order: SortOrder) -> Vec<Expense> {
+    let mut expenses = _get_active_expenses(&caller());
+    expenses.sort_by(|a, b| {
+        apply_sort_order(compare_by_field(a, b, field), order).then_with(|| a.id.cmp(&b.id))
+    });
+    expenses
+}
+
+// Sorts by each key in turn, so later keys only order expenses the earlier
+// ones consider equal, e.g. by category and then by amount descending.
+// Remaining ties go to the lowest id.
+#[ic_cdk::query]
+fn get_expenses_sorted_multi(keys: Vec<SortKey>) -> Result<Vec<Expense>, Error> {
+    if keys.is_empty() || keys.len() > MAX_SORT_KEYS {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Between 1 and {} sort keys are allowed, got {}",
+                MAX_SORT_KEYS,
+                keys.len()
+            ),
+        });
+    }
+    let mut expenses = _get_active_expenses(&caller());
+    expenses.sort_by(|a, b| {
+        keys.iter()
+            .fold(Ordering::Equal, |ordering, key| {
+                ordering
+                    .then_with(|| apply_sort_order(compare_by_field(a, b, key.field), key.order))
+            })
+            .then_with(|| a.id.cmp(&b.id))
+    });
+    Ok(expenses)
+}
+
+// Date range covered by the caller's expenses, for "tracking since" labels
+// and chart sizing, found in a single pass. `None` when there are none.
+#[ic_cdk::query]
+fn get_tracking_span() -> Option<TrackingSpan> {
+    let owner = caller();
+    let (first_date, last_date, total_count) = STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, expense)| expense)
+            .filter(|expense| expense.owner == owner && !expense.archived)
+            .fold((u64::MAX, 0, 0), |(first, last, count), expense| {
+                (first.min(expense.date), last.max(expense.date), count + 1)
+            })
+    });
+    if total_count == 0 {
+        return None;
+    }
+    Some(TrackingSpan {
+        first_date,
+        last_date,
+        span_days: last_date / NANOS_PER_DAY - first_date / NANOS_PER_DAY + 1,
+        total_count,
+    })
+}
+
+// The caller's earliest expense by date; ties go to the lowest id
+#[ic_cdk::query]
+fn get_oldest_expense() -> Option<Expense> {
+    let owner = caller();
+    STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, expense)| expense)
+            .filter(|expense| expense.owner == owner && !expense.archived)
+            .min_by_key(|expense| (expense.date, expense.id))
+    })
+}
+
+// The caller's latest expense by date; ties go to the highest id
+#[ic_cdk::query]
+fn get_newest_expense() -> Option<Expense> {
+    let owner = caller();
+    STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, expense)| expense)
+            .filter(|expense| expense.owner == owner && !expense.archived)
+            .max_by_key(|expense| (expense.date, expense.id))
+    })
+}
+
+// Returns the `n` largest expenses, breaking ties by the most recent date.
+// Only `n` entries are held in memory at a time, using a min-heap whose
+// smallest element is evicted whenever it grows past `n`.
+#[ic_cdk::query]
+fn get_top_expenses(n: usize) -> Result<Vec<Expense>, Error> {
+    if !(1..=MAX_TOP_EXPENSES).contains(&n) {
+        return Err(Error::InvalidInput {
+            msg: format!("n must be between 1 and {}, got {}", MAX_TOP_EXPENSES, n),
+        });
+    }
+    let owner = caller();
+    STORAGE.with(|service| {
+        let service = service.borrow();
+        let mut heap: BinaryHeap<Reverse<(i64, u64, u64)>> = BinaryHeap::with_capacity(n + 1);
+        for (id, expense) in service.iter() {
+            if expense.owner != owner || expense.archived || !is_spending(&expense) {
+                continue;
+            }
+            heap.push(Reverse((expense.amount, expense.date, id)));
+            if heap.len() > n {
+                heap.pop();
+            }
+        }
+        Ok(heap
+            .into_sorted_vec()
+            .into_iter()
+            .filter_map(|Reverse((_, _, id))| service.get(&id))
+            .collect())
+    })
+}
+
+// Kept for backward compatibility; same as `get_expenses_sorted(Amount, Desc)`
+#[ic_cdk::query]
+fn get_expenses_sorted_by_amount() -> Vec<Expense> {
+    get_expenses_sorted(SortField::Amount, SortOrder::Desc)
+}
+
+#[ic_cdk::update]
+fn add_expense(mut payload: ExpensePayload) -> Result<Expense, Error> {
+    let owner = caller();
+    if owner == Principal::anonymous() {
+        return Err(Error::Unauthorized {
+            msg: "Anonymous principals cannot add expenses".to_string(),
+        });
+    }
+    with_rate_limit(&owner, 1, || {
+        payload.date = normalize_date(payload.date)?;
+        apply_category_rules(&owner, &mut payload);
+        validate_expense_payload(&owner, &mut payload)?;
+        // A retry must return the original expense, not be reported as a duplicate
+        if let Some(expense) = find_idempotent_expense(&owner, &payload.idempotency_key) {
+            return Ok(expense);
+        }
+        if let Some(existing_id) = find_duplicate(&owner, &payload)? {
+            return Err(Error::DuplicateExpense { existing_id });
+        }
+        insert_new_expense(owner, payload, EntryType::Expense)
+    })
+}
+
+// Same as `add_expense`, but also reports anything about the stored expense
+// that looks like a mistake without rejecting it
+#[ic_cdk::update]
+fn add_expense_checked(payload: ExpensePayload) -> Result<CheckedExpense, Error> {
+    let expense = add_expense(payload)?;
+    let warnings = expense_warnings(&expense, time());
+    Ok(CheckedExpense { expense, warnings })
+}
+
+// Same as `add_expense` but records the payload as income, which
+// `calculate_net_balance` adds instead of subtracting
+#[ic_cdk::update]
+fn add_income(mut payload: ExpensePayload) -> Result<Expense, Error> {
+    let owner = caller();
+    if owner == Principal::anonymous() {
+        return Err(Error::Unauthorized {
+            msg: "Anonymous principals cannot add income".to_string(),
+        });
+    }
+    if payload.reimbursable == Some(true) {
+        return Err(Error::InvalidInput {
+            msg: "Income cannot be reimbursable".to_string(),
+        });
+    }
+    with_rate_limit(&owner, 1, || {
+        payload.date = normalize_date(payload.date)?;
+        validate_expense_payload(&owner, &mut payload)?;
+        insert_new_expense(owner, payload, EntryType::Income)
+    })
+}
+
+// Same as `add_expense` but skips the duplicate check, for repeats that are intentional
+#[ic_cdk::update]
+fn add_expense_force(mut payload: ExpensePayload) -> Result<Expense, Error> {
+    let owner = caller();
+    if owner == Principal::anonymous() {
+        return Err(Error::Unauthorized {
+            msg: "Anonymous principals cannot add expenses".to_string(),
+        });
+    }
+    with_rate_limit(&owner, 1, || {
+        payload.date = normalize_date(payload.date)?;
+        apply_category_rules(&owner, &mut payload);
+        _add_expense(owner, payload)
+    })
+}
+
+// Inserts all payloads or none of them: every payload is validated before
+// anything is written.
+#[ic_cdk::update]
+fn add_expenses_batch(payloads: Vec<ExpensePayload>) -> Result<Vec<Expense>, Error> {
+    let owner = caller();
+    if owner == Principal::anonymous() {
+        return Err(Error::Unauthorized {
+            msg: "Anonymous principals cannot add expenses".to_string(),
+        });
+    }
+    if payloads.is_empty() || payloads.len() > MAX_BATCH_SIZE {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Batch must contain between 1 and {} expenses, got {}",
+                MAX_BATCH_SIZE,
+                payloads.len()
+            ),
+        });
+    }
+    with_rate_limit(&owner, payloads.len(), || {
+        _add_expenses_atomically(owner, payloads)
+    })
+}
+
+// Imports a JSON array of expense payloads, e.g.
+// `[{"description": "Coffee", "amount": 3.5, "date": 1700000000000000000, "currency": "USD"}]`.
+// Like `add_expenses_batch`, either every record is imported or none is.
+#[ic_cdk::update]
+fn import_expenses_json(data: String) -> Result<u64, Error> {
+    let owner = caller();
+    if owner == Principal::anonymous() {
+        return Err(Error::Unauthorized {
+            msg: "Anonymous principals cannot import expenses".to_string(),
+        });
+    }
+    if data.len() > MAX_IMPORT_JSON_BYTES {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Import data is {} bytes, but at most {} bytes are allowed",
+                data.len(),
+                MAX_IMPORT_JSON_BYTES
+            ),
+        });
+    }
+    let payloads: Vec<ExpensePayload> =
+        serde_json::from_str(&data).map_err(|error| Error::InvalidInput {
+            msg: format!(
+                "Import data is not a valid JSON array of expenses: {}",
+                error
+            ),
+        })?;
+    if payloads.is_empty() || payloads.len() > MAX_IMPORT_SIZE {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Import must contain between 1 and {} expenses, got {}",
+                MAX_IMPORT_SIZE,
+                payloads.len()
+            ),
+        });
+    }
+    with_rate_limit(&owner, payloads.len(), || {
+        _add_expenses_atomically(owner, payloads)
+    })
+    .map(|expenses| expenses.len() as u64)
+}
+
+// Applies a mix of additions, updates, and deletions all-or-nothing: every
+// operation is checked before anything is written, and the error names the
+// first failing index. Each expense may be updated or deleted only once per
+// batch. Results are returned in the order of `ops`.
+#[ic_cdk::update]
+fn apply_operations(ops: Vec<ExpenseOp>) -> Result<Vec<OpResult>, Error> {
+    let owner = caller();
+    if owner == Principal::anonymous() {
+        return Err(Error::Unauthorized {
+            msg: "Anonymous principals cannot change expenses".to_string(),
+        });
+    }
+    if ops.is_empty() || ops.len() > MAX_BATCH_SIZE {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Batch must contain between 1 and {} operations, got {}",
+                MAX_BATCH_SIZE,
+                ops.len()
+            ),
+        });
+    }
+    let adds = ops
+        .iter()
+        .filter(|op| matches!(op, ExpenseOp::Add { .. }))
+        .count();
+    with_rate_limit(&owner, adds, || _apply_operations(owner, ops))
+}
+
+// Helper function doing the work of `apply_operations` once the batch passed
+// the rate limit
+fn _apply_operations(owner: Principal, ops: Vec<ExpenseOp>) -> Result<Vec<OpResult>, Error> {
+    let mut checked = Vec::with_capacity(ops.len());
+    let mut touched = BTreeSet::new();
+    for (index, op) in ops.into_iter().enumerate() {
+        if let ExpenseOp::Update { id, .. } | ExpenseOp::Delete { id } = &op {
+            if !touched.insert(*id) {
+                return Err(Error::InvalidInput {
+                    msg: format!(
+                        "Operation at index {} changes expense with id={} a second time",
+                        index, id
+                    ),
+                });
+            }
+        }
+        let result = match op {
+            ExpenseOp::Add { mut payload } => normalize_date(payload.date)
+                .and_then(|date| {
+                    payload.date = date;
+                    apply_category_rules(&owner, &mut payload);
+                    validate_expense_payload(&owner, &mut payload)
+                })
+                .map(|_| CheckedOp::Add(payload)),
+            ExpenseOp::Update {
+                id,
+                expected_version,
+                mut payload,
+                if_unmodified_since,
+            } => check_update(id, expected_version, if_unmodified_since, &mut payload)
+                .map(|expense| CheckedOp::Update(Box::new(expense), payload)),
+            ExpenseOp::Delete { id } => check_delete(id).map(CheckedOp::Delete),
+        };
+        checked.push(result.map_err(|error| {
+            error.prefixed(&format!("Operation at index {} is invalid", index))
+        })?);
+    }
+    let additions = checked
+        .iter()
+        .filter(|op| matches!(op, CheckedOp::Add(_)))
+        .count() as u64;
+    let deletions = checked
+        .iter()
+        .filter(|op| matches!(op, CheckedOp::Delete(_)))
+        .count() as u64;
+    ensure_quota(&owner, additions.saturating_sub(deletions))?;
+    ensure_expense_ids_free(additions)?;
+
+    // Deletions go first so that the additions fit within the quota checked above
+    for op in &checked {
+        if let CheckedOp::Delete(expense) = op {
+            remove_expense(expense);
+        }
+    }
+    checked
+        .into_iter()
+        .map(|op| match op {
+            CheckedOp::Add(payload) => insert_new_expense(owner, payload, EntryType::Expense)
+                .map(|expense| OpResult::Added { expense }),
+            CheckedOp::Update(expense, payload) => {
+                apply_update(*expense, payload).map(|expense| OpResult::Updated { expense })
+            }
+            CheckedOp::Delete(expense) => Ok(OpResult::Deleted { expense }),
+        })
+        .collect()
+}
+
+// Replaces an expense, provided it is still at `expected_version`. When
+// `if_unmodified_since` is given, the expense must also not have changed
+// after that timestamp: its `updated_at`, or `created_at` if it was never
+// updated, has to be at or before it. Either check failing returns
+// `VersionConflict`.
+#[ic_cdk::update]
+fn update_expense(
+    id: u64,
+    expected_version: u64,
+    mut payload: ExpensePayload,
+    if_unmodified_since: Option<u64>,
+) -> Result<Expense, Error> {
+    let expense = check_update(id, expected_version, if_unmodified_since, &mut payload)?;
+    apply_update(expense, payload)
+}
+
+// Applies only the fields set in `patch`, each validated like in
+// `update_expense`. The expense is left untouched, including `updated_at`,
+// when no field actually changes.
+#[ic_cdk::update]
+fn patch_expense(id: u64, patch: ExpensePatch) -> Result<Expense, Error> {
+    match STORAGE.with(|service| service.borrow().get(&id)) {
+        Some(mut expense) => {
+            ensure_owner(&expense)?;
+            if expense.archived {
+                return Err(Error::InvalidInput {
+                    msg: format!(
+                        "Couldn't patch expense with id={}. Restore it from the archive first.",
+                        id
+                    ),
+                });
+            }
+            ensure_unlocked(expense.date)?;
+            let previous = expense.clone();
+            if let Some(description) = patch.description {
+                let description = description.trim().to_string();
+                if description.is_empty() {
+                    return Err(Error::InvalidInput {
+                        msg: "Description must not be empty".to_string(),
+                    });
+                }
+                expense.description = description;
+            }
+            if let Some(amount) = patch.amount {
+                let amount = round_amount(amount);
+                expense.amount = validate_amount(amount)?;
+                ensure_covers_refunds(&expense, expense.amount)?;
+                if !expense.splits.is_empty() {
+                    let mut splits: Vec<SplitPayload> = expense
+                        .splits
+                        .iter()
+                        .map(|split| SplitPayload {
+                            participant: split.participant.clone(),
+                            share: from_minor_units(split.share),
+                        })
+                        .collect();
+                    validate_splits(&mut splits, amount)?;
+                }
+                let allocated: i64 = expense
+                    .category_splits
+                    .iter()
+                    .map(|split| split.amount)
+                    .sum();
+                if !expense.category_splits.is_empty() && allocated != expense.amount {
+                    return Err(Error::InvalidInput {
+                        msg: format!(
+                            "Category splits add up to {} but the new amount is {}. Change both with update_expense.",
+                            format_minor_units(allocated),
+                            format_minor_units(expense.amount)
+                        ),
+                    });
+                }
+            }
+            if let Some(date) = patch.date {
+                let date = normalize_date(date)?;
+                validate_date(date)?;
+                ensure_unlocked(date)?;
+                expense.date = date;
+            }
+            if expense.description == previous.description
+                && expense.amount == previous.amount
+                && expense.date == previous.date
+            {
+                return Ok(expense);
+            }
+
+            let mut largest = expense.clone();
+            largest.tags = vec!["x".repeat(MAX_TAG_LEN); MAX_TAGS];
+            largest.updated_at = Some(u64::MAX);
+            largest.archived = true;
+            largest.archived_at = Some(u64::MAX);
+            largest.reimbursed_at = Some(u64::MAX);
+            validate_encoded_size(&largest)?;
+
+            expense.status = ExpenseStatus::Pending;
+            expense.updated_at = Some(time());
+            expense.version += 1;
+            do_insert(&expense)?;
+            record_history(&previous, &expense);
+            Ok(expense)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Couldn't patch expense with id={}. Expense not found.", id),
+        }),
+    }
+}
+
+#[ic_cdk::update]
+fn add_tag_to_expense(id: u64, tag: String) -> Result<Expense, Error> {
+    let tag = normalize_tag(&tag)?;
+    match _get_expense(&id) {
+        Some(mut expense) => {
+            ensure_owner(&expense)?;
+            ensure_unlocked(expense.date)?;
+            if expense.tags.contains(&tag) {
+                return Ok(expense);
+            }
+            if expense.tags.len() >= MAX_TAGS {
+                return Err(Error::InvalidInput {
+                    msg: format!("An expense can have at most {} tags", MAX_TAGS),
+                });
+            }
+            expense.tags.push(tag);
+            expense.updated_at = Some(time());
+            expense.version += 1;
+            validate_encoded_size(&expense)?;
+            do_insert(&expense)?;
+            Ok(expense)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Couldn't tag expense with id={}. Expense not found.", id),
+        }),
+    }
+}
+
+#[ic_cdk::update]
+fn remove_tag_from_expense(id: u64, tag: String) -> Result<Expense, Error> {
+    let tag = tag.trim().to_lowercase();
+    match _get_expense(&id) {
+        Some(mut expense) => {
+            ensure_owner(&expense)?;
+            ensure_unlocked(expense.date)?;
+            if !expense.tags.contains(&tag) {
+                return Err(Error::NotFound {
+                    msg: format!("Expense with id={} has no tag {:?}", id, tag),
+                });
+            }
+            expense.tags.retain(|existing| *existing != tag);
+            expense.updated_at = Some(time());
+            expense.version += 1;
+            do_insert(&expense)?;
+            Ok(expense)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Couldn't untag expense with id={}. Expense not found.", id),
+        }),
+    }
+}
+
+#[ic_cdk::query]
+fn get_expense_history(id: u64) -> Result<Vec<ExpenseHistory>, Error> {
+    match _get_expense(&id) {
+        Some(expense) => {
+            ensure_owner(&expense)?;
+            let mut history: Vec<ExpenseHistory> = HISTORY_STORAGE.with(|service| {
+                service
+                    .borrow()
+                    .range((id, 0)..=(id, u64::MAX))
+                    .map(|(_, entry)| entry)
+                    .collect()
+            });
+            history.sort_by_key(|entry| entry.changed_at);
+            Ok(history)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Expense with id={} not found", id),
+        }),
+    }
+}
+
+// The caller's most recent additions, updates, and deletions across all of
+// their expenses, newest first. Only the latest `MAX_ACTIVITY_EVENTS` writes
+// of all users together are kept.
+#[ic_cdk::query]
+fn get_activity_feed(limit: usize) -> Result<Vec<ActivityEvent>, Error> {
+    if limit == 0 || limit as u64 > MAX_ACTIVITY_EVENTS {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "limit must be between 1 and {}, got {}",
+                MAX_ACTIVITY_EVENTS, limit
+            ),
+        });
+    }
+    let owner = caller();
+    let mut events: Vec<ActivityEvent> = ACTIVITY_STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, event)| event)
+            .filter(|event| event.owner == owner)
+            .collect()
+    });
+    events.reverse();
+    events.truncate(limit);
+    Ok(events)
+}
+
+// Approves a pending expense so it counts towards totals. Restricted to the
+// approver.
+#[ic_cdk::update]
+fn approve_expense(id: u64) -> Result<Expense, Error> {
+    ensure_approver()?;
+    let mut expense = pending_expense_for_review(id)?;
+    expense.status = ExpenseStatus::Approved;
+    expense.updated_at = Some(time());
+    expense.version += 1;
+    do_insert(&expense)?;
+    Ok(expense)
+}
+
+// Rejects a pending expense. The reason is added to the expense's comments,
+// where the owner can read it. Restricted to the approver.
+#[ic_cdk::update]
+fn reject_expense(id: u64, reason: String) -> Result<Expense, Error> {
+    ensure_approver()?;
+    let reason = reason.trim().to_string();
+    if reason.is_empty() || reason.chars().count() > MAX_COMMENT_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Reason must be between 1 and {} characters long",
+                MAX_COMMENT_LEN
+            ),
+        });
+    }
+    let mut expense = pending_expense_for_review(id)?;
+    ensure_comment_capacity(id)?;
+    expense.status = ExpenseStatus::Rejected;
+    expense.updated_at = Some(time());
+    expense.version += 1;
+    do_insert(&expense)?;
+    append_comment(id, format!("Rejected: {}", reason));
+    Ok(expense)
+}
+
+// Every user's non-archived expenses awaiting review, oldest first.
+// Restricted to the approver.
+#[ic_cdk::query]
+fn list_pending_expenses() -> Result<Vec<Expense>, Error> {
+    ensure_approver()?;
+    let mut expenses: Vec<Expense> = STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, expense)| expense)
+            .filter(|expense| !expense.archived && expense.status == ExpenseStatus::Pending)
+            .collect()
+    });
+    expenses.sort_by_key(|expense| (expense.created_at, expense.id));
+    Ok(expenses)
+}
+
+// Records that a reimbursable expense has been paid back. The amount and
+// description stay the same, so nothing is added to the change history.
+#[ic_cdk::update]
+fn mark_reimbursed(id: u64) -> Result<Expense, Error> {
+    match _get_expense(&id) {
+        Some(mut expense) => {
+            ensure_owner(&expense)?;
+            ensure_unlocked(expense.date)?;
+            if !expense.reimbursable {
+                return Err(Error::InvalidInput {
+                    msg: format!("Expense with id={} is not reimbursable", id),
+                });
+            }
+            if expense.reimbursed_at.is_some() {
+                return Err(Error::InvalidInput {
+                    msg: format!("Expense with id={} is already reimbursed", id),
+                });
+            }
+            let now = time();
+            expense.reimbursed_at = Some(now);
+            expense.updated_at = Some(now);
+            expense.version += 1;
+            do_insert(&expense)?;
+            Ok(expense)
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "Couldn't mark expense with id={} as reimbursed. Expense not found.",
+                id
+            ),
+        }),
+    }
+}
+
+// The caller's reimbursable expenses that have not been paid back yet,
+// oldest first
+#[ic_cdk::query]
+fn list_pending_reimbursements() -> Vec<Expense> {
+    let mut expenses: Vec<Expense> = _get_active_expenses(&caller())
+        .into_iter()
+        .filter(|expense| expense.reimbursable && expense.reimbursed_at.is_none())
+        .collect();
+    expenses.sort_by_key(|expense| (expense.date, expense.id));
+    expenses
+}
+
+// What the caller is still owed for reimbursable expenses, net of refunds
+#[ic_cdk::query]
+fn total_pending_reimbursement() -> f64 {
+    from_minor_units(list_pending_reimbursements().iter().map(net_amount).sum())
+}
+
+// Appends a timestamped comment to an expense, e.g. to track a reimbursement.
+// Archived expenses can still be commented on.
+#[ic_cdk::update]
+fn add_comment(expense_id: u64, text: String) -> Result<Comment, Error> {
+    let text = text.trim().to_string();
+    if text.is_empty() || text.chars().count() > MAX_COMMENT_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Comment must be between 1 and {} characters long",
+                MAX_COMMENT_LEN
+            ),
+        });
+    }
+    match _get_expense(&expense_id) {
+        Some(expense) => {
+            ensure_owner(&expense)?;
+            ensure_comment_capacity(expense_id)?;
+            Ok(append_comment(expense_id, text))
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "Couldn't comment on expense with id={}. Expense not found.",
+                expense_id
+            ),
+        }),
+    }
+}
+
+// Records a refund against an expense, e.g. for a returned item. Refunds
+// may not add up to more than the expense amount; `refunded_total` on the
+// expense keeps their sum.
+#[ic_cdk::update]
+fn add_refund(expense_id: u64, amount: f64, date: u64) -> Result<Expense, Error> {
+    let refund_amount = validate_amount(amount)?;
+    let date = normalize_date(date)?;
+    validate_date(date)?;
+    match _get_expense(&expense_id) {
+        Some(mut expense) => {
+            ensure_owner(&expense)?;
+            ensure_unlocked(expense.date)?;
+            if expense.archived {
+                return Err(Error::InvalidInput {
+                    msg: format!(
+                        "Couldn't refund expense with id={}. Restore it from the archive first.",
+                        expense_id
+                    ),
+                });
+            }
+            if expense.entry_type != EntryType::Expense {
+                return Err(Error::InvalidInput {
+                    msg: format!(
+                        "Entry with id={} is income and cannot be refunded",
+                        expense_id
+                    ),
+                });
+            }
+            let remaining = net_amount(&expense);
+            if refund_amount > remaining {
+                return Err(Error::InvalidInput {
+                    msg: format!(
+                        "Refund of {} exceeds the {} left to refund on expense with id={}",
+                        format_minor_units(refund_amount),
+                        format_minor_units(remaining),
+                        expense_id
+                    ),
+                });
+            }
+            expense.refunded_total += refund_amount;
+            expense.updated_at = Some(time());
+            expense.version += 1;
+            do_insert(&expense)?;
+            REFUND_STORAGE.with(|service| {
+                let mut service = service.borrow_mut();
+                let next_seq = service
+                    .range((expense_id, 0)..=(expense_id, u64::MAX))
+                    .last()
+                    .map_or(0, |((_, seq), _)| seq + 1);
+                service.insert(
+                    (expense_id, next_seq),
+                    Refund {
+                        expense_id,
+                        amount: refund_amount,
+                        date,
+                        created_at: time(),
+                    },
+                );
+            });
+            Ok(expense)
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "Couldn't refund expense with id={}. Expense not found.",
+                expense_id
+            ),
+        }),
+    }
+}
+
+// Refunds recorded against an expense, oldest first
+#[ic_cdk::query]
+fn get_refunds(expense_id: u64) -> Result<Vec<Refund>, Error> {
+    match _get_expense(&expense_id) {
+        Some(expense) => {
+            ensure_owner(&expense)?;
+            Ok(REFUND_STORAGE.with(|service| {
+                service
+                    .borrow()
+                    .range((expense_id, 0)..=(expense_id, u64::MAX))
+                    .map(|(_, refund)| refund)
+                    .collect()
+            }))
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Expense with id={} not found", expense_id),
+        }),
+    }
+}
+
+// What an expense cost after refunds
+#[ic_cdk::query]
+fn get_net_amount(expense_id: u64) -> Result<f64, Error> {
+    match _get_expense(&expense_id) {
+        Some(expense) => {
+            ensure_owner(&expense)?;
+            Ok(from_minor_units(net_amount(&expense)))
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Expense with id={} not found", expense_id),
+        }),
+    }
+}
+
+// Comments on an expense, oldest first
+#[ic_cdk::query]
+fn get_comments(expense_id: u64) -> Result<Vec<Comment>, Error> {
+    match _get_expense(&expense_id) {
+        Some(expense) => {
+            ensure_owner(&expense)?;
+            Ok(COMMENT_STORAGE.with(|service| {
+                service
+                    .borrow()
+                    .range((expense_id, 0)..=(expense_id, u64::MAX))
+                    .map(|(_, comment)| comment)
+                    .collect()
+            }))
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Expense with id={} not found", expense_id),
+        }),
+    }
+}
+
+#[ic_cdk::update]
+fn archive_expense(id: u64) -> Result<Expense, Error> {
+    match _get_expense(&id) {
+        Some(mut expense) => {
+            ensure_owner(&expense)?;
+            ensure_unlocked(expense.date)?;
+            if expense.archived {
+                return Err(Error::InvalidInput {
+                    msg: format!("Expense with id={} is already archived", id),
+                });
+            }
+            let now = time();
+            expense.archived = true;
+            expense.archived_at = Some(now);
+            expense.updated_at = Some(now);
+            expense.version += 1;
+            do_insert(&expense)?;
+            Ok(expense)
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "Couldn't archive expense with id={}. Expense not found.",
+                id
+            ),
+        }),
+    }
+}
+
+#[ic_cdk::update]
+fn restore_expense(id: u64) -> Result<Expense, Error> {
+    match _get_expense(&id) {
+        Some(mut expense) => {
+            ensure_owner(&expense)?;
+            ensure_unlocked(expense.date)?;
+            if !expense.archived {
+                return Err(Error::InvalidInput {
+                    msg: format!("Expense with id={} is not archived", id),
+                });
+            }
+            expense.archived = false;
+            expense.archived_at = None;
+            expense.updated_at = Some(time());
+            expense.version += 1;
+            do_insert(&expense)?;
+            Ok(expense)
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "Couldn't restore expense with id={}. Expense not found.",
+                id
+            ),
+        }),
+    }
+}
+
+#[ic_cdk::update]
+fn delete_expense(id: u64) -> Result<Expense, Error> {
+    let expense = check_delete(id)?;
+    remove_expense(&expense);
+    Ok(expense)
+}
+
+// Permanently removes the caller's archived expenses dated within the
+// inclusive range, together with their change history. As with
+// `delete_expense`, expenses that are not archived are kept. Returns how many
+// were removed.
+#[ic_cdk::update]
+fn delete_expenses_by_date_range(start_date: u64, end_date: u64) -> Result<u64, Error> {
+    if start_date > end_date {
+        return Err(Error::InvalidInput {
+            msg: "start_date must not be after end_date".to_string(),
+        });
+    }
+    let owner = caller();
+    // The map can't be modified while it is being iterated, so collect the
+    // ids first and remove them in a second pass.
+    let matching: Vec<Expense> = STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, expense)| expense)
+            .filter(|expense| {
+                expense.owner == owner
+                    && expense.archived
+                    && expense.date >= start_date
+                    && expense.date <= end_date
+            })
+            .collect()
+    });
+    for expense in &matching {
+        ensure_unlocked(expense.date)?;
+    }
+    for expense in &matching {
+        remove_expense(expense);
+    }
+    Ok(matching.len() as u64)
+}
+
+// Wipes every expense of every user, together with their change history,
+// comments, refunds, and idempotency keys, and resets the id counter.
+// Restricted to controllers and guarded by a confirmation phrase.
+#[ic_cdk::update]
+fn clear_all_expenses(confirmation: String) -> Result<u64, Error> {
+    ensure_admin()?;
+    if confirmation != CLEAR_ALL_CONFIRMATION {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Pass \"{}\" to confirm deleting all expenses",
+                CLEAR_ALL_CONFIRMATION
+            ),
+        });
+    }
+    let removed = wipe_expenses();
+    ID_COUNTER
+        .with(|counter| counter.borrow_mut().set(0))
+        .map_err(|error| Error::StorageError {
+            msg: format!("Cannot reset id counter: {:?}", error),
+        })?;
+    Ok(removed)
+}
+
+// Serializes every expense and category, with both id counters, into one
+// versioned blob that `restore_snapshot` can load into a fresh canister.
+// The blob is returned in a single reply, so it is subject to the IC's 2 MiB
+// response limit. Restricted to controllers.
+#[ic_cdk::query]
+fn export_snapshot() -> Result<Vec<u8>, Error> {
+    ensure_admin()?;
+    let snapshot = Snapshot {
+        expenses: STORAGE.with(|service| {
+            service
+                .borrow()
+                .iter()
+                .map(|(_, expense)| expense.to_bytes().into_owned())
+                .collect()
+        }),
+        categories: CATEGORY_STORAGE.with(|service| {
+            service
+                .borrow()
+                .iter()
+                .map(|(_, category)| category)
+                .collect()
+        }),
+        next_expense_id: ID_COUNTER.with(|counter| *counter.borrow().get()),
+        next_category_id: CATEGORY_ID_COUNTER.with(|counter| *counter.borrow().get()),
+    };
+    let body = Encode!(&snapshot).map_err(|error| Error::StorageError {
+        msg: format!("Cannot encode snapshot: {}", error),
+    })?;
+    let mut data = Vec::with_capacity(SNAPSHOT_MAGIC.len() + 1 + body.len());
+    data.extend_from_slice(SNAPSHOT_MAGIC);
+    data.push(SNAPSHOT_VERSION);
+    data.extend_from_slice(&body);
+    Ok(data)
+}
+
+// Replaces all expenses and categories with the contents of a blob from
+// `export_snapshot` and returns how many expenses were restored. Change
+// history, comments, refunds, and idempotency keys are cleared, and budgets
+// for categories missing from the snapshot are dropped. Everything is
+// validated before any state changes. Restricted to controllers and guarded
+// by a confirmation phrase.
+#[ic_cdk::update]
+fn restore_snapshot(data: Vec<u8>, confirmation: String) -> Result<u64, Error> {
+    ensure_admin()?;
+    if confirmation != RESTORE_CONFIRMATION {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Pass \"{}\" to confirm replacing all expenses",
+                RESTORE_CONFIRMATION
+            ),
+        });
+    }
+    let body = data
+        .strip_prefix(SNAPSHOT_MAGIC)
+        .ok_or_else(|| Error::InvalidInput {
+            msg: "Data is not an expense snapshot".to_string(),
+        })?;
+    let (&version, body) = body.split_first().ok_or_else(|| Error::InvalidInput {
+        msg: "Snapshot is missing its version".to_string(),
+    })?;
+    if version == 0 || version > SNAPSHOT_VERSION {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Snapshot version {} is not supported; this canister reads versions 1 to {}",
+                version, SNAPSHOT_VERSION
+            ),
+        });
+    }
+    let snapshot = Decode!(body, Snapshot).map_err(|error| Error::InvalidInput {
+        msg: format!("Snapshot cannot be decoded: {}", error),
+    })?;
+
+    let mut expenses: BTreeMap<u64, Expense> = BTreeMap::new();
+    for (index, bytes) in snapshot.expenses.iter().enumerate() {
+        let expense: Expense = try_decode_with_defaults(bytes).map_err(|msg| {
+            Error::InvalidInput { msg }.prefixed(&format!("Expense at index {} is invalid", index))
+        })?;
+        validate_encoded_size(&expense)
+            .map_err(|error| error.prefixed(&format!("Expense at index {} is invalid", index)))?;
+        if expenses.insert(expense.id, expense).is_some() {
+            return Err(Error::InvalidInput {
+                msg: format!("Snapshot contains expense at index {} twice", index),
+            });
+        }
+    }
+    let mut categories: BTreeMap<u64, Category> = BTreeMap::new();
+    for category in snapshot.categories {
+        if category.to_bytes().len() > Category::MAX_SIZE as usize {
+            return Err(Error::InvalidInput {
+                msg: format!("Category with id={} is too large to store", category.id),
+            });
+        }
+        let id = category.id;
+        if categories.insert(id, category).is_some() {
+            return Err(Error::InvalidInput {
+                msg: format!("Snapshot contains category with id={} twice", id),
+            });
+        }
+    }
+
+    wipe_expenses();
+    let count = expenses.len() as u64;
+    let next_expense_id = expenses
+        .last_key_value()
+        .map_or(0, |(id, _)| id + 1)
+        .max(snapshot.next_expense_id);
+    STORAGE.with(|service| {
+        let mut service = service.borrow_mut();
+        for (id, expense) in expenses {
+            service.insert(id, expense);
+        }
+    });
+    rebuild_owner_counts();
+
+    let next_category_id = categories
+        .last_key_value()
+        .map_or(0, |(id, _)| id + 1)
+        .max(snapshot.next_category_id);
+    CATEGORY_STORAGE.with(|service| {
+        let mut service = service.borrow_mut();
+        let ids: Vec<u64> = service.iter().map(|(id, _)| id).collect();
+        for id in ids {
+            service.remove(&id);
+        }
+        for (id, category) in &categories {
+            service.insert(*id, category.clone());
+        }
+    });
+    BUDGET_STORAGE.with(|service| {
+        let mut service = service.borrow_mut();
+        let stale: Vec<u64> = service
+            .iter()
+            .map(|(id, _)| id)
+            .filter(|id| !categories.contains_key(id))
+            .collect();
+        for id in stale {
+            service.remove(&id);
+        }
+    });
+    // Rules are kept only while their category was restored for the same owner
+    CATEGORY_RULE_STORAGE.with(|service| {
+        let mut service = service.borrow_mut();
+        let stale: Vec<u64> = service
+            .iter()
+            .filter(|(_, rule)| {
+                categories
+                    .get(&rule.category_id)
+                    .is_none_or(|category| category.owner != rule.owner)
+            })
+            .map(|(id, _)| id)
+            .collect();
+        for id in stale {
+            service.remove(&id);
+        }
+    });
+
+    ID_COUNTER
+        .with(|counter| counter.borrow_mut().set(next_expense_id))
+        .map_err(|error| Error::StorageError {
+            msg: format!("Cannot update id counter: {:?}", error),
+        })?;
+    CATEGORY_ID_COUNTER
+        .with(|counter| counter.borrow_mut().set(next_category_id))
+        .map_err(|error| Error::StorageError {
+            msg: format!("Cannot update category id counter: {:?}", error),
+        })?;
+    Ok(count)
+}
+
+// Moves the expense id counter past the highest stored id, e.g. after a
+// migration imported expenses with explicit ids, and returns the next id that
+// will be handed out. The counter is never moved backwards, so ids of deleted
+// expenses are not reused. Restricted to controllers.
+#[ic_cdk::update]
+fn reconcile_id_counter() -> Result<u64, Error> {
+    ensure_admin()?;
+    let next_expense_id = STORAGE.with(|service| {
+        service
+            .borrow()
+            .last_key_value()
+            .map_or(0, |(id, _)| id + 1)
+    });
+    ID_COUNTER.with(|counter| {
+        let current_value = *counter.borrow().get();
+        if current_value >= next_expense_id {
+            return Ok(current_value);
+        }
+        counter
+            .borrow_mut()
+            .set(next_expense_id)
+            .map_err(|error| Error::StorageError {
+                msg: format!("Cannot update id counter: {:?}", error),
+            })?;
+        Ok(next_expense_id)
+    })
+}
+
+// Closes every period up to and including `end_date`: expenses dated then
+// can no longer be updated, patched, scaled, recategorized, refunded, or
+// deleted. The lock can only move forward; use `unlock_period` to reopen. Restricted to controllers.
+#[ic_cdk::update]
+fn lock_period(end_date: u64) -> Result<u64, Error> {
+    ensure_admin()?;
+    let current = locked_through();
+    if end_date == 0 || end_date < current {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "end_date must be a non-zero timestamp no earlier than the current lock at {}",
+                current
+            ),
+        });
+    }
+    set_locked_through(end_date)?;
+    Ok(end_date)
+}
+
+// Removes the period lock. Restricted to controllers.
+#[ic_cdk::update]
+fn unlock_period() -> Result<(), Error> {
+    ensure_admin()?;
+    set_locked_through(0)
+}
+
+// The timestamp periods are locked through, if any
+#[ic_cdk::query]
+fn get_locked_through() -> Option<u64> {
+    Some(locked_through()).filter(|&locked| locked > 0)
+}
+
+#[ic_cdk::update]
+fn add_category(name: String) -> Result<Category, Error> {
+    let owner = caller();
+    if owner == Principal::anonymous() {
+        return Err(Error::Unauthorized {
+            msg: "Anonymous principals cannot add categories".to_string(),
+        });
+    }
+    let name = name.trim().to_string();
+    if name.is_empty() || name.len() > MAX_CATEGORY_NAME_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Category name must be between 1 and {} bytes long",
+                MAX_CATEGORY_NAME_LEN
+            ),
+        });
+    }
+
+    let id = next_id(&CATEGORY_ID_COUNTER, |id| {
+        CATEGORY_STORAGE.with(|service| service.borrow().contains_key(id))
+    })?;
+
+    let category = Category {
+        id,
+        owner,
+        name,
+        created_at: time(),
+    };
+    CATEGORY_STORAGE.with(|service| service.borrow_mut().insert(category.id, category.clone()));
+    Ok(category)
+}
+
+#[ic_cdk::query]
+fn list_categories() -> Vec<Category> {
+    let owner = caller();
+    CATEGORY_STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, category)| category.owner == owner)
+            .map(|(_, category)| category)
+            .collect()
+    })
+}
+
+// Moves every expense of the caller in `from_category` to `to_category`,
+// archived ones included. `None` on either side means uncategorized.
+// Returns how many expenses were moved.
+#[ic_cdk::update]
+fn recategorize_expenses(
+    from_category: Option<u64>,
+    to_category: Option<u64>,
+) -> Result<u64, Error> {
+    let owner = caller();
+    validate_category(&owner, from_category)?;
+    validate_category(&owner, to_category)?;
+    if from_category == to_category {
+        return Ok(0);
+    }
+    // Collect first, then write, so the map isn't borrowed mutably while it
+    // is being iterated
+    let matching: Vec<Expense> = STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, expense)| expense)
+            .filter(|expense| expense.owner == owner && expense.category_id == from_category)
+            .collect()
+    });
+    for expense in &matching {
+        ensure_unlocked(expense.date)?;
+    }
+    let now = time();
+    for mut expense in matching.iter().cloned() {
+        expense.category_id = to_category;
+        expense.updated_at = Some(now);
+        expense.version += 1;
+        do_insert(&expense)?;
+    }
+    Ok(matching.len() as u64)
+}
+
+// Multiplies the amount of every expense matching `filter` by `factor`,
+// e.g. after a currency redenomination, and returns how many were changed.
+// Split shares and refunds are scaled along with the amount, every change is
+// recorded in the history, and changed expenses go back to `Pending` review.
+// All results are validated before anything is written.
+#[ic_cdk::update]
+fn scale_amounts(filter: ExpenseFilter, factor: f64) -> Result<u64, Error> {
+    if !factor.is_finite() || factor <= 0.0 {
+        return Err(Error::InvalidInput {
+            msg: format!("factor must be a positive number, got {}", factor),
+        });
+    }
+    let matching = query_expenses(filter);
+    // Each update holds the expense before and after, and its scaled refunds
+    let mut updates: Vec<(Expense, Expense, Vec<RefundEntry>)> = Vec::with_capacity(matching.len());
+    for previous in matching {
+        ensure_unlocked(previous.date)?;
+        let context = format!("Expense with id={} cannot be scaled", previous.id);
+        let mut expense = previous.clone();
+        expense.amount = scale_minor_units(previous.amount, factor)
+            .and_then(|amount| validate_amount(from_minor_units(amount)))
+            .map_err(|error| error.prefixed(&context))?;
+        if !expense.splits.is_empty() {
+            let shares: Vec<i64> = expense.splits.iter().map(|split| split.share).collect();
+            let shares = scale_parts(&shares, factor, expense.amount)
+                .map_err(|error| error.prefixed(&context))?;
+            for (split, share) in expense.splits.iter_mut().zip(shares) {
+                split.share = share;
+            }
+        }
+        if !expense.category_splits.is_empty() {
+            let parts: Vec<i64> = expense
+                .category_splits
+                .iter()
+                .map(|split| split.amount)
+                .collect();
+            let parts = scale_parts(&parts, factor, expense.amount)
+                .map_err(|error| error.prefixed(&context))?;
+            for (split, amount) in expense.category_splits.iter_mut().zip(parts) {
+                split.amount = amount;
+            }
+        }
+        let mut refunds: Vec<RefundEntry> = REFUND_STORAGE.with(|service| {
+            service
+                .borrow()
+                .range((previous.id, 0)..=(previous.id, u64::MAX))
+                .collect()
+        });
+        if !refunds.is_empty() {
+            expense.refunded_total = scale_minor_units(previous.refunded_total, factor)
+                .map_err(|error| error.prefixed(&context))?
+                .min(expense.amount);
+            let amounts: Vec<i64> = refunds.iter().map(|(_, refund)| refund.amount).collect();
+            let amounts = scale_parts(&amounts, factor, expense.refunded_total)
+                .map_err(|error| error.prefixed(&context))?;
+            for ((_, refund), amount) in refunds.iter_mut().zip(amounts) {
+                refund.amount = amount;
+            }
+        }
+        updates.push((previous, expense, refunds));
+    }
+
+    let count = updates.len() as u64;
+    let now = time();
+    for (previous, mut expense, refunds) in updates {
+        expense.status = ExpenseStatus::Pending;
+        expense.updated_at = Some(now);
+        expense.version += 1;
+        do_insert(&expense)?;
+        record_history(&previous, &expense);
+        REFUND_STORAGE.with(|service| {
+            let mut service = service.borrow_mut();
+            for (key, refund) in refunds {
+                service.insert(key, refund);
+            }
+        });
+    }
+    Ok(count)
+}
+
+#[ic_cdk::update]
+fn delete_category(id: u64) -> Result<Category, Error> {
+    let category = _get_owned_category(&caller(), &id)?;
+    let attached = STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, expense)| {
+                expense.category_id == Some(id)
+                    || expense
+                        .category_splits
+                        .iter()
+                        .any(|split| split.category_id == id)
+            })
+            .count()
+    });
+    if attached > 0 {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Couldn't delete category with id={}. {} expense(s) still reference it.",
+                id, attached
+            ),
+        });
+    }
+    let templates = RECURRING_STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, template)| template.category_id == Some(id))
+            .count()
+    });
+    if templates > 0 {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Couldn't delete category with id={}. {} recurring template(s) still reference it.",
+                id, templates
+            ),
+        });
+    }
+    CATEGORY_STORAGE.with(|service| service.borrow_mut().remove(&id));
+    BUDGET_STORAGE.with(|service| service.borrow_mut().remove(&id));
+    // Rules for the category have nothing left to assign
+    CATEGORY_RULE_STORAGE.with(|service| {
+        let mut service = service.borrow_mut();
+        let rule_ids: Vec<u64> = service
+            .iter()
+            .filter(|(_, rule)| rule.category_id == id)
+            .map(|(rule_id, _)| rule_id)
+            .collect();
+        for rule_id in rule_ids {
+            service.remove(&rule_id);
+        }
+    });
+    Ok(category)
+}
+
+// The caller's expenses, archived ones included, that reference a category
+// which no longer exists, either as `category_id` or in a category split.
+// Sorted by id.
+#[ic_cdk::query]
+fn orphaned_expenses() -> Vec<Expense> {
+    _get_owned_expenses(&caller())
+        .into_iter()
+        .filter(has_missing_category)
+        .collect()
+}
+
+// Drops the dangling category references found by `orphaned_expenses` and
+// returns how many expenses were changed. A missing `category_id` becomes
+// `None`; category splits naming a missing category are removed altogether,
+// since the remaining parts would no longer add up to the amount.
+#[ic_cdk::update]
+fn clean_orphaned_categories() -> Result<u64, Error> {
+    let orphaned = orphaned_expenses();
+    for expense in &orphaned {
+        ensure_unlocked(expense.date)?;
+    }
+    let now = time();
+    for mut expense in orphaned.iter().cloned() {
+        if !expense.category_id.is_none_or(category_exists) {
+            expense.category_id = None;
+        }
+        if !expense
+            .category_splits
+            .iter()
+            .all(|split| category_exists(split.category_id))
+        {
+            expense.category_splits.clear();
+        }
+        expense.updated_at = Some(now);
+        expense.version += 1;
+        do_insert(&expense)?;
+    }
+    Ok(orphaned.len() as u64)
+}
+
+// Adds a rule that files new expenses whose description contains `keyword`
+// under `category_id`. Rules are tried in the order they were added.
+#[ic_cdk::update]
+fn add_rule(keyword: String, category_id: u64) -> Result<CategoryRule, Error> {
+    let owner = caller();
+    if owner == Principal::anonymous() {
+        return Err(Error::Unauthorized {
+            msg: "Anonymous principals cannot add rules".to_string(),
+        });
+    }
+    let keyword = keyword.trim().to_lowercase();
+    if keyword.is_empty() || keyword.chars().count() > MAX_RULE_KEYWORD_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Keyword must be between 1 and {} characters long",
+                MAX_RULE_KEYWORD_LEN
+            ),
+        });
+    }
+    _get_owned_category(&owner, &category_id)?;
+    let rules = list_rules();
+    if rules.len() >= MAX_RULES_PER_OWNER {
+        return Err(Error::InvalidInput {
+            msg: format!("A user can have at most {} rules", MAX_RULES_PER_OWNER),
+        });
+    }
+    if rules.iter().any(|rule| rule.keyword == keyword) {
+        return Err(Error::InvalidInput {
+            msg: format!("A rule for keyword {:?} already exists", keyword),
+        });
+    }
+
+    let id = next_id(&CATEGORY_RULE_ID_COUNTER, |id| {
+        CATEGORY_RULE_STORAGE.with(|service| service.borrow().contains_key(id))
+    })?;
+    let rule = CategoryRule {
+        id,
+        owner,
+        keyword,
+        category_id,
+    };
+    CATEGORY_RULE_STORAGE.with(|service| service.borrow_mut().insert(rule.id, rule.clone()));
+    Ok(rule)
+}
+
+// The caller's rules in the order they are tried
+#[ic_cdk::query]
+fn list_rules() -> Vec<CategoryRule> {
+    _get_owned_rules(&caller())
+}
+
+#[ic_cdk::update]
+fn delete_rule(id: u64) -> Result<CategoryRule, Error> {
+    match CATEGORY_RULE_STORAGE.with(|service| service.borrow().get(&id)) {
+        Some(rule) if rule.owner == caller() => {
+            CATEGORY_RULE_STORAGE.with(|service| service.borrow_mut().remove(&id));
+            Ok(rule)
+        }
+        Some(_) => Err(Error::Unauthorized {
+            msg: format!("Caller is not the owner of rule with id={}", id),
+        }),
+        None => Err(Error::NotFound {
+            msg: format!("Couldn't delete rule with id={}. Rule not found.", id),
+        }),
+    }
+}
+
+// Files the caller's existing uncategorized expenses with the first rule
+// that matches each, and returns how many were changed. Income, archived
+// expenses, and expenses with category splits are left alone.
+#[ic_cdk::update]
+fn apply_rules_to_existing() -> Result<u64, Error> {
+    let owner = caller();
+    let rules = list_rules();
+    if rules.is_empty() {
+        return Ok(0);
+    }
+    // Collect first, then write, so the map isn't borrowed mutably while it
+    // is being iterated
+    let matching: Vec<(Expense, u64)> = _get_active_expenses(&owner)
+        .into_iter()
+        .filter(|expense| {
+            expense.entry_type == EntryType::Expense
+                && expense.category_id.is_none()
+                && expense.category_splits.is_empty()
+        })
+        .filter_map(|expense| {
+            let category_id = matching_rule(&rules, &expense.description)?;
+            Some((expense, category_id))
+        })
+        .collect();
+    for (expense, _) in &matching {
+        ensure_unlocked(expense.date)?;
+    }
+    let now = time();
+    for (mut expense, category_id) in matching.iter().cloned() {
+        expense.category_id = Some(category_id);
+        expense.updated_at = Some(now);
+        expense.version += 1;
+        do_insert(&expense)?;
+    }
+    Ok(matching.len() as u64)
+}
+
+#[ic_cdk::update]
+fn add_recurring(payload: RecurringPayload) -> Result<RecurringTemplate, Error> {
+    let owner = caller();
+    if owner == Principal::anonymous() {
+        return Err(Error::Unauthorized {
+            msg: "Anonymous principals cannot add recurring expenses".to_string(),
+        });
+    }
+    if payload.interval_days == 0 {
+        return Err(Error::InvalidInput {
+            msg: "interval_days must be at least 1".to_string(),
+        });
+    }
+    if payload.next_due == 0 {
+        return Err(Error::InvalidInput {
+            msg: "next_due must be a non-zero timestamp in nanoseconds".to_string(),
+        });
+    }
+    // Check the expenses this will generate, so they cannot fail validation
+    // later.
+    let mut expense_payload = ExpensePayload {
+        description: payload.description,
+        amount: payload.amount,
+        date: time(),
+        category_id: payload.category_id,
+        currency: Some(payload.currency),
+        ..Default::default()
+    };
+    validate_expense_payload(&owner, &mut expense_payload)?;
+
+    let id = next_id(&RECURRING_ID_COUNTER, |id| {
+        RECURRING_STORAGE.with(|service| service.borrow().contains_key(id))
+    })?;
+
+    let template = RecurringTemplate {
+        id,
+        owner,
+        description: expense_payload.description,
+        amount: to_minor_units(expense_payload.amount)?,
+        currency: resolve_currency(expense_payload.currency),
+        interval_days: payload.interval_days,
+        next_due: payload.next_due,
+        category_id: payload.category_id,
+    };
+    RECURRING_STORAGE.with(|service| service.borrow_mut().insert(template.id, template.clone()));
+    Ok(template)
+}
+
+#[ic_cdk::query]
+fn list_recurring() -> Vec<RecurringTemplate> {
+    let owner = caller();
+    RECURRING_STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, template)| template.owner == owner)
+            .map(|(_, template)| template)
+            .collect()
+    })
+}
+
+#[ic_cdk::update]
+fn delete_recurring(id: u64) -> Result<RecurringTemplate, Error> {
+    match RECURRING_STORAGE.with(|service| service.borrow().get(&id)) {
+        Some(template) if template.owner == caller() => {
+            RECURRING_STORAGE.with(|service| service.borrow_mut().remove(&id));
+            Ok(template)
+        }
+        Some(_) => Err(Error::Unauthorized {
+            msg: format!(
+                "Caller is not the owner of recurring template with id={}",
+                id
+            ),
+        }),
+        None => Err(Error::NotFound {
+            msg: format!(
+                "Couldn't delete recurring template with id={}. Template not found.",
+                id
+            ),
+        }),
+    }
+}
+
+// Generates expenses for every recurring template that has come due. Each
+// template catches up on at most `MAX_RECURRING_CATCH_UP` missed intervals
+// per call; remaining ones are generated on the next call. An occurrence that
+// cannot be recorded, e.g. because the owner's quota is full, is skipped and
+// reported in `failures`, and its template waits for the next call.
+#[ic_cdk::update]
+fn materialize_due_recurring() -> MaterializeResult {
+    let now = time();
+    let due: Vec<RecurringTemplate> = RECURRING_STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, template)| template.next_due <= now)
+            .map(|(_, template)| template)
+            .collect()
+    });
+
+    let mut created = Vec::new();
+    let mut failures = Vec::new();
+    for mut template in due {
+        let mut generated = 0;
+        while template.next_due <= now && generated < MAX_RECURRING_CATCH_UP {
+            let payload = ExpensePayload {
+                description: template.description.clone(),
+                amount: from_minor_units(template.amount),
+                date: template.next_due,
+                category_id: template.category_id,
+                currency: Some(template.currency.clone()),
+                merchant: None,
+                note: None,
+                payment_method: None,
+                splits: None,
+                category_splits: None,
+                idempotency_key: None,
+                receipt_url: None,
+                reimbursable: None,
+            };
+            let result = _add_expense(template.owner, payload);
+            let due_date = template.next_due;
+            template.next_due = template
+                .next_due
+                .saturating_add(template.interval_days.saturating_mul(NANOS_PER_DAY));
+            generated += 1;
+            match result {
+                Ok(expense) => created.push(expense),
+                Err(error) => {
+                    failures.push(RecurringFailure {
+                        template_id: template.id,
+                        due_date,
+                        error,
+                    });
+                    break;
+                }
+            }
+        }
+        RECURRING_STORAGE.with(|service| service.borrow_mut().insert(template.id, template));
+    }
+    MaterializeResult { created, failures }
+}
+
+#[ic_cdk::update]
+fn add_planned(payload: PlannedPayload) -> Result<PlannedExpense, Error> {
+    let owner = caller();
+    if owner == Principal::anonymous() {
+        return Err(Error::Unauthorized {
+            msg: "Anonymous principals cannot add planned expenses".to_string(),
+        });
+    }
+    if payload.due_date == 0 {
+        return Err(Error::InvalidInput {
+            msg: "due_date must be a non-zero timestamp in nanoseconds".to_string(),
+        });
+    }
+    // Check the expense this will become, so marking it paid cannot fail
+    // validation later.
+    let mut expense_payload = ExpensePayload {
+        description: payload.description,
+        amount: payload.amount,
+        date: time(),
+        currency: Some(payload.currency),
+        ..Default::default()
+    };
+    validate_expense_payload(&owner, &mut expense_payload)?;
+
+    let id = next_id(&PLANNED_ID_COUNTER, |id| {
+        PLANNED_STORAGE.with(|service| service.borrow().contains_key(id))
+    })?;
+
+    let planned = PlannedExpense {
+        id,
+        owner,
+        description: expense_payload.description,
+        amount: to_minor_units(expense_payload.amount)?,
+        currency: resolve_currency(expense_payload.currency),
+        due_date: payload.due_date,
+        paid: false,
+        expense_id: None,
+    };
+    PLANNED_STORAGE.with(|service| service.borrow_mut().insert(planned.id, planned.clone()));
+    Ok(planned)
+}
+
+// Unpaid planned expenses due between now and `within_days` from now,
+// soonest first
+#[ic_cdk::query]
+fn list_upcoming(within_days: u64) -> Vec<PlannedExpense> {
+    let owner = caller();
+    let now = time();
+    let until = now.saturating_add(within_days.saturating_mul(NANOS_PER_DAY));
+    let mut upcoming: Vec<PlannedExpense> = PLANNED_STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, planned)| planned)
+            .filter(|planned| {
+                planned.owner == owner
+                    && !planned.paid
+                    && planned.due_date >= now
+                    && planned.due_date <= until
+            })
+            .collect()
+    });
+    upcoming.sort_by_key(|planned| (planned.due_date, planned.id));
+    upcoming
+}
+
+// Records a planned expense as a real expense dated now and flags it paid
+#[ic_cdk::update]
+fn mark_planned_paid(id: u64) -> Result<Expense, Error> {
+    match PLANNED_STORAGE.with(|service| service.borrow().get(&id)) {
+        Some(mut planned) if planned.owner == caller() => {
+            if planned.paid {
+                return Err(Error::InvalidInput {
+                    msg: format!("Planned expense with id={} is already paid", id),
+                });
+            }
+            let payload = ExpensePayload {
+                description: planned.description.clone(),
+                amount: from_minor_units(planned.amount),
+                date: time(),
+                currency: Some(planned.currency.clone()),
+                ..Default::default()
+            };
+            let expense = _add_expense(planned.owner, payload)?;
+            planned.paid = true;
+            planned.expense_id = Some(expense.id);
+            PLANNED_STORAGE.with(|service| service.borrow_mut().insert(planned.id, planned));
+            Ok(expense)
+        }
+        Some(_) => Err(Error::Unauthorized {
+            msg: format!("Caller is not the owner of planned expense with id={}", id),
+        }),
+        None => Err(Error::NotFound {
+            msg: format!(
+                "Couldn't mark planned expense with id={} as paid. Planned expense not found.",
+                id
+            ),
+        }),
+    }
+}
+
+#[ic_cdk::update]
+fn set_budget(category_id: u64, monthly_limit: f64) -> Result<Budget, Error> {
+    _get_owned_category(&caller(), &category_id)?;
+    let monthly_limit = to_minor_units(monthly_limit)?;
+    if monthly_limit <= 0 {
+        return Err(Error::InvalidInput {
+            msg: "Monthly limit must be greater than zero".to_string(),
+        });
+    }
+    let budget = Budget {
+        category_id,
+        monthly_limit,
+    };
+    BUDGET_STORAGE.with(|service| service.borrow_mut().insert(category_id, budget.clone()));
+    Ok(budget)
+}
+
+#[ic_cdk::query]
+fn get_budget(category_id: u64) -> Result<Budget, Error> {
+    _get_owned_category(&caller(), &category_id)?;
+    match BUDGET_STORAGE.with(|service| service.borrow().get(&category_id)) {
+        Some(budget) => Ok(budget),
+        None => Err(Error::NotFound {
+            msg: format!("No budget set for category with id={}", category_id),
+        }),
+    }
+}
+
+#[ic_cdk::query]
+fn check_budget_status(category_id: u64, year: u32, month: u32) -> Result<BudgetStatus, Error> {
+    if !(1..=12).contains(&month) {
+        return Err(Error::InvalidInput {
+            msg: format!("Month must be between 1 and 12, got {}", month),
+        });
+    }
+    let budget = get_budget(category_id)?;
+    let spent: i64 = _get_active_expenses(&caller())
+        .iter()
+        .filter(|expense| is_spending(expense))
+        .filter(|expense| {
+            let (expense_year, expense_month, _) = civil_date_from_nanos(expense.date);
+            expense_year == year && expense_month == month
+        })
+        .flat_map(category_parts)
+        .filter(|&(part_category, _)| part_category == Some(category_id))
+        .map(|(_, amount)| amount)
+        .sum();
+    Ok(BudgetStatus {
+        limit: from_minor_units(budget.monthly_limit),
+        spent: from_minor_units(spent),
+        remaining: from_minor_units(budget.monthly_limit - spent),
+        over_budget: spent > budget.monthly_limit,
+    })
+}
+
+#[ic_cdk::update]
+fn set_exchange_rate(code: String, rate_to_base: f64) -> Result<f64, Error> {
+    ensure_admin()?;
+    validate_currency_code(&code)?;
+    if !rate_to_base.is_finite() || rate_to_base <= 0.0 {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Exchange rate must be a positive number, got {}",
+                rate_to_base
+            ),
+        });
+    }
+    EXCHANGE_RATE_STORAGE.with(|service| {
+        service
+            .borrow_mut()
+            .insert(CurrencyCode(code), rate_to_base)
+    });
+    Ok(rate_to_base)
+}
+
+#[ic_cdk::query]
+fn get_exchange_rate(code: String) -> Result<f64, Error> {
+    validate_currency_code(&code)?;
+    match EXCHANGE_RATE_STORAGE.with(|service| service.borrow().get(&CurrencyCode(code.clone()))) {
+        Some(rate) => Ok(rate),
+        None => Err(Error::NotFound {
+            msg: format!("No exchange rate set for currency {}", code),
+        }),
+    }
+}
+
+#[ic_cdk::update]
+fn add_allowed_currency(code: String) -> Result<Vec<String>, Error> {
+    ensure_admin()?;
+    validate_currency_code(&code)?;
+    ALLOWED_CURRENCY_STORAGE.with(|service| service.borrow_mut().insert(CurrencyCode(code), ()));
+    Ok(list_allowed_currencies())
+}
+
+#[ic_cdk::update]
+fn remove_allowed_currency(code: String) -> Result<Vec<String>, Error> {
+    ensure_admin()?;
+    validate_currency_code(&code)?;
+    match ALLOWED_CURRENCY_STORAGE
+        .with(|service| service.borrow_mut().remove(&CurrencyCode(code.clone())))
+    {
+        Some(()) => Ok(list_allowed_currencies()),
+        None => Err(Error::NotFound {
+            msg: format!("Currency {} is not on the allowed list", code),
+        }),
+    }
+}
+
+// Currencies expenses may use, in alphabetical order. Empty means any valid
+// code is accepted.
+#[ic_cdk::query]
+fn list_allowed_currencies() -> Vec<String> {
+    ALLOWED_CURRENCY_STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(CurrencyCode(code), _)| code)
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn get_settings() -> Settings {
+    current_settings()
+}
+
+#[ic_cdk::update]
+fn update_settings(settings: Settings) -> Result<Settings, Error> {
+    ensure_admin()?;
+    validate_currency_code(&settings.base_currency)?;
+    if !(1..=MAX_PER_PAGE).contains(&settings.default_per_page) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "default_per_page must be between 1 and {}, got {}",
+                MAX_PER_PAGE, settings.default_per_page
+            ),
+        });
+    }
+    if !settings.max_amount.is_finite() || settings.max_amount <= 0.0 {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "max_amount must be a positive number, got {}",
+                settings.max_amount
+            ),
+        });
+    }
+    to_minor_units(settings.max_amount).map_err(|error| error.prefixed("max_amount"))?;
+    if settings.max_expenses_per_owner == 0 {
+        return Err(Error::InvalidInput {
+            msg: "max_expenses_per_owner must be at least 1".to_string(),
+        });
+    }
+    if settings.decimal_places > MAX_DECIMAL_PLACES {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "decimal_places must be at most {}, the precision amounts are stored with, got {}",
+                MAX_DECIMAL_PLACES, settings.decimal_places
+            ),
+        });
+    }
+    if !(0.0..=MAX_AMOUNT_EPSILON).contains(&settings.amount_epsilon) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "amount_epsilon must be between 0 and {}, got {}",
+                MAX_AMOUNT_EPSILON, settings.amount_epsilon
+            ),
+        });
+    }
+    if settings.rate_limit_max_calls == 0 || settings.rate_limit_window_secs == 0 {
+        return Err(Error::InvalidInput {
+            msg: "rate_limit_max_calls and rate_limit_window_secs must be at least 1".to_string(),
+        });
+    }
+    SETTINGS
+        .with(|cell| cell.borrow_mut().set(settings.clone()))
+        .map_err(|error| Error::StorageError {
+            msg: format!("Cannot store settings: {:?}", error),
+        })?;
+    Ok(settings)
+}
+
+// Helper function to read the current canister settings
+fn current_settings() -> Settings {
+    SETTINGS.with(|cell| cell.borrow().get().clone())
+}
+
+// Helper function to validate a payload and insert it as a new expense of `owner`
+fn _add_expense(owner: Principal, mut payload: ExpensePayload) -> Result<Expense, Error> {
+    validate_expense_payload(&owner, &mut payload)?;
+    insert_new_expense(owner, payload, EntryType::Expense)
+}
+
+// Helper function to validate every payload before inserting any of them
+fn _add_expenses_atomically(
+    owner: Principal,
+    mut payloads: Vec<ExpensePayload>,
+) -> Result<Vec<Expense>, Error> {
+    ensure_quota(&owner, payloads.len() as u64)?;
+    for (index, payload) in payloads.iter_mut().enumerate() {
+        normalize_date(payload.date)
+            .and_then(|date| {
+                payload.date = date;
+                apply_category_rules(&owner, payload);
+                validate_expense_payload(&owner, payload)
+            })
+            .map_err(|error| error.prefixed(&format!("Expense at index {} is invalid", index)))?;
+    }
+    ensure_expense_ids_free(payloads.len() as u64)?;
+    payloads
+        .into_iter()
+        .map(|payload| insert_new_expense(owner, payload, EntryType::Expense))
+        .collect()
+}
+
+// Helper function to insert an already validated payload as a new entry of `owner`
+fn insert_new_expense(
+    owner: Principal,
+    payload: ExpensePayload,
+    entry_type: EntryType,
+) -> Result<Expense, Error> {
+    if let Some(expense) = find_idempotent_expense(&owner, &payload.idempotency_key) {
+        return Ok(expense);
+    }
+    ensure_quota(&owner, 1)?;
+    let idempotency_key = payload.idempotency_key.clone();
+    let id = next_id(&ID_COUNTER, |id| {
+        STORAGE.with(|service| service.borrow().contains_key(id))
+    })?;
+
+    let mut new_expense = expense_from_payload(id, owner, payload, time())?;
+    new_expense.entry_type = entry_type;
+    do_insert(&new_expense)?;
+    adjust_owner_count(&owner, 1);
+    if let Some(key) = idempotency_key {
+        remember_idempotency_key(owner, key, id);
+    }
+    Ok(new_expense)
+}
+
+// Helper function to make every check `update_expense` does before writing,
+// returning the stored expense that `payload` will replace
+fn check_update(
+    id: u64,
+    expected_version: u64,
+    if_unmodified_since: Option<u64>,
+    payload: &mut ExpensePayload,
+) -> Result<Expense, Error> {
+    let Some(expense) = _get_expense(&id) else {
+        return Err(Error::NotFound {
+            msg: format!("Couldn't update expense with id={}. Expense not found.", id),
+        });
+    };
+    ensure_owner(&expense)?;
+    let last_modified = expense.updated_at.unwrap_or(expense.created_at);
+    if expense.version != expected_version
+        || if_unmodified_since.is_some_and(|since| last_modified > since)
+    {
+        return Err(Error::VersionConflict {
+            current_version: expense.version,
+        });
+    }
+    if expense.archived {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Couldn't update expense with id={}. Restore it from the archive first.",
+                id
+            ),
+        });
+    }
+    ensure_unlocked(expense.date)?;
+    payload.date = normalize_date(payload.date)?;
+    ensure_unlocked(payload.date)?;
+    validate_expense_payload(&expense.owner, payload)?;
+    ensure_covers_refunds(&expense, to_minor_units(payload.amount)?)?;
+    Ok(expense)
+}
+
+// Helper function to write a payload that passed `check_update` over `expense`
+fn apply_update(mut expense: Expense, payload: ExpensePayload) -> Result<Expense, Error> {
+    let previous = expense.clone();
+    expense.description = payload.description;
+    expense.amount = to_minor_units(payload.amount)?;
+    expense.date = payload.date;
+    expense.category_id = payload.category_id;
+    expense.currency = resolve_currency(payload.currency);
+    expense.merchant = payload.merchant;
+    expense.note = payload.note;
+    expense.payment_method = payload.payment_method;
+    expense.splits = splits_from_payload(payload.splits)?;
+    expense.category_splits = category_splits_from_payload(payload.category_splits)?;
+    expense.receipt_url = payload.receipt_url;
+    if let Some(reimbursable) = payload.reimbursable {
+        expense.reimbursable = reimbursable;
+        if !reimbursable {
+            expense.reimbursed_at = None;
+        }
+    }
+    // A reviewed expense that changes has to be reviewed again
+    expense.status = ExpenseStatus::Pending;
+    expense.updated_at = Some(time());
+    expense.version += 1;
+    do_insert(&expense)?;
+    record_history(&previous, &expense);
+    Ok(expense)
+}
+
+// Helper function to make every check `delete_expense` does, returning the
+// expense to remove
+fn check_delete(id: u64) -> Result<Expense, Error> {
+    let Some(expense) = _get_expense(&id) else {
+        return Err(Error::NotFound {
+            msg: format!("Couldn't delete expense with id={}. Expense not found.", id),
+        });
+    };
+    ensure_owner(&expense)?;
+    if !expense.archived {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Couldn't delete expense with id={}. Archive it before deleting.",
+                id
+            ),
+        });
+    }
+    ensure_unlocked(expense.date)?;
+    Ok(expense)
+}
+
+// Helper function to build a fresh expense record from a payload
+fn expense_from_payload(
+    id: u64,
+    owner: Principal,
+    payload: ExpensePayload,
+    created_at: u64,
+) -> Result<Expense, Error> {
+    Ok(Expense {
+        id,
+        owner,
+        description: payload.description,
+        amount: to_minor_units(payload.amount)?,
+        date: payload.date,
+        category_id: payload.category_id,
+        currency: resolve_currency(payload.currency),
+        merchant: payload.merchant,
+        note: payload.note,
+        payment_method: payload.payment_method,
+        tags: Vec::new(),
+        entry_type: EntryType::Expense,
+        splits: splits_from_payload(payload.splits)?,
+        category_splits: category_splits_from_payload(payload.category_splits)?,
+        receipt_url: payload.receipt_url,
+        refunded_total: 0,
+        status: ExpenseStatus::Pending,
+        reimbursable: payload.reimbursable.unwrap_or(false),
+        reimbursed_at: None,
+        created_at,
+        updated_at: None,
+        version: 0,
+        archived: false,
+        archived_at: None,
+    })
+}
+
+// Helper function to find the expense an unexpired idempotency key created
+fn find_idempotent_expense(owner: &Principal, key: &Option<String>) -> Option<Expense> {
+    let key = IdempotencyKey {
+        owner: *owner,
+        key: key.clone()?,
+    };
+    let (expense_id, seen_at) = IDEMPOTENCY_STORAGE.with(|service| service.borrow().get(&key))?;
+    if time().saturating_sub(seen_at) > IDEMPOTENCY_KEY_TTL_NANOS {
+        return None;
+    }
+    _get_expense(&expense_id)
+}
+
+// Helper function to remember which expense an idempotency key created. Once
+// `MAX_IDEMPOTENCY_KEYS` are stored, expired keys are dropped, and if none
+// have expired the oldest one makes room.
+fn remember_idempotency_key(owner: Principal, key: String, expense_id: u64) {
+    let now = time();
+    IDEMPOTENCY_STORAGE.with(|service| {
+        let mut service = service.borrow_mut();
+        if service.len() >= MAX_IDEMPOTENCY_KEYS {
+            let expired: Vec<IdempotencyKey> = service
+                .iter()
+                .filter(|(_, (_, seen_at))| {
+                    now.saturating_sub(*seen_at) > IDEMPOTENCY_KEY_TTL_NANOS
+                })
+                .map(|(key, _)| key)
+                .collect();
+            for key in &expired {
+                service.remove(key);
+            }
+            if expired.is_empty() {
+                let oldest = service
+                    .iter()
+                    .min_by_key(|(_, (_, seen_at))| *seen_at)
+                    .map(|(key, _)| key);
+                if let Some(oldest) = oldest {
+                    service.remove(&oldest);
+                }
+            }
+        }
+        service.insert(IdempotencyKey { owner, key }, (expense_id, now));
+    });
+}
+
+// Helper function to take the next id from a counter and advance it. An id
+// that `in_use` reports as taken, e.g. because the counter was reset while
+// records survived, is refused rather than handed out to overwrite them;
+// the counter is left where it is so nothing is skipped silently.
+fn next_id(
+    counter: &'static std::thread::LocalKey<RefCell<IdCell>>,
+    in_use: impl Fn(&u64) -> bool,
+) -> Result<u64, Error> {
+    counter.with(|counter| {
+        let current_value = *counter.borrow().get();
+        if in_use(&current_value) {
+            return Err(Error::StorageError {
+                msg: format!(
+                    "Id {} is already in use; the id counter is behind the stored records",
+                    current_value
+                ),
+            });
+        }
+        let next_value = current_value
+            .checked_add(1)
+            .ok_or_else(|| Error::StorageError {
+                msg: "Id counter is exhausted".to_string(),
+            })?;
+        counter
+            .borrow_mut()
+            .set(next_value)
+            .map_err(|error| Error::StorageError {
+                msg: format!("Cannot increment id counter: {:?}", error),
+            })
+    })
+}
+
+// Helper function to check that the next `count` expense ids are free before
+// a batch writes anything. `next_id` would refuse a taken id anyway, but only
+// after the earlier records of the batch had been stored.
+fn ensure_expense_ids_free(count: u64) -> Result<(), Error> {
+    let start = ID_COUNTER.with(|counter| *counter.borrow().get());
+    let taken = STORAGE.with(|service| {
+        service
+            .borrow()
+            .range(start..start.saturating_add(count))
+            .next()
+            .map(|(id, _)| id)
+    });
+    match taken {
+        Some(id) => Err(Error::StorageError {
+            msg: format!(
+                "Id {} is already in use; the id counter is behind the stored records",
+                id
+            ),
+        }),
+        None => Ok(()),
+    }
+}
+
+// Helper function to move a counter forward so it is at least `min_value`
+fn restore_counter(counter: &'static std::thread::LocalKey<RefCell<IdCell>>, min_value: u64) {
+    counter.with(|counter| {
+        let current_value = *counter.borrow().get();
+        if current_value < min_value {
+            counter
+                .borrow_mut()
+                .set(min_value)
+                .expect("Cannot restore id counter");
+        }
+    });
+}
+
+// Helper function to append a change to an expense's history, dropping the
+// oldest entries once `MAX_HISTORY_PER_EXPENSE` is exceeded
+fn record_history(previous: &Expense, current: &Expense) {
+    let id = current.id;
+    HISTORY_STORAGE.with(|service| {
+        let mut service = service.borrow_mut();
+        let keys: Vec<(u64, u64)> = service
+            .range((id, 0)..=(id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        let next_seq = keys.last().map_or(0, |(_, seq)| seq + 1);
+        service.insert(
+            (id, next_seq),
+            ExpenseHistory {
+                expense_id: id,
+                changed_at: current.updated_at.unwrap_or_else(time),
+                old_amount: previous.amount,
+                new_amount: current.amount,
+                old_description: previous.description.clone(),
+                new_description: current.description.clone(),
+            },
+        );
+        let excess = (keys.len() + 1).saturating_sub(MAX_HISTORY_PER_EXPENSE);
+        for key in keys.iter().take(excess) {
+            service.remove(key);
+        }
+    });
+}
+
+// Helper function to append a write to the activity feed, dropping the
+// oldest events beyond `MAX_ACTIVITY_EVENTS`
+fn record_activity(kind: ActivityKind, expense: &Expense) {
+    ACTIVITY_STORAGE.with(|service| {
+        let mut service = service.borrow_mut();
+        let next_seq = service.last_key_value().map_or(0, |(seq, _)| seq + 1);
+        service.insert(
+            next_seq,
+            ActivityEvent {
+                kind,
+                expense_id: expense.id,
+                owner: expense.owner,
+                timestamp: time(),
+                amount: expense.amount,
+            },
+        );
+        while service.len() > MAX_ACTIVITY_EVENTS {
+            if let Some((seq, _)) = service.first_key_value() {
+                service.remove(&seq);
+            }
+        }
+    });
+}
+
+// Helper function to remove every expense of every user along with
+// everything keyed by expense id. Returns how many expenses were removed.
+fn wipe_expenses() -> u64 {
+    let ids: Vec<u64> = STORAGE.with(|service| service.borrow().iter().map(|(id, _)| id).collect());
+    STORAGE.with(|service| {
+        let mut service = service.borrow_mut();
+        for id in &ids {
+            service.remove(id);
+        }
+    });
+    HISTORY_STORAGE.with(|service| {
+        let mut service = service.borrow_mut();
+        let keys: Vec<(u64, u64)> = service.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            service.remove(&key);
+        }
+    });
+    COMMENT_STORAGE.with(|service| {
+        let mut service = service.borrow_mut();
+        let keys: Vec<(u64, u64)> = service.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            service.remove(&key);
+        }
+    });
+    REFUND_STORAGE.with(|service| {
+        let mut service = service.borrow_mut();
+        let keys: Vec<(u64, u64)> = service.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            service.remove(&key);
+        }
+    });
+    OWNER_COUNT_STORAGE.with(|service| {
+        let mut service = service.borrow_mut();
+        let keys: Vec<OwnerKey> = service.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            service.remove(&key);
+        }
+    });
+    ACTIVITY_STORAGE.with(|service| {
+        let mut service = service.borrow_mut();
+        let keys: Vec<u64> = service.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            service.remove(&key);
+        }
+    });
+    // Ids may be handed out again afterwards, so stored keys would point at
+    // the wrong expenses
+    IDEMPOTENCY_STORAGE.with(|service| {
+        let mut service = service.borrow_mut();
+        let keys: Vec<IdempotencyKey> = service.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            service.remove(&key);
+        }
+    });
+    ids.len() as u64
+}
+
+// Helper function to permanently remove an expense, its history, and its
+// place in the owner's quota
+fn remove_expense(expense: &Expense) {
+    STORAGE.with(|service| service.borrow_mut().remove(&expense.id));
+    record_activity(ActivityKind::Deleted, expense);
+    remove_history(expense.id);
+    remove_comments(expense.id);
+    remove_refunds(expense.id);
+    adjust_owner_count(&expense.owner, -1);
+}
+
+// Helper function to allow each caller at most `rate_limit_max_calls` added
+// expenses within a sliding window of `rate_limit_window_secs`, so a runaway
+// client cannot burn cycles and fill memory. `adds` is the most expenses
+// `insert` may store and is checked up front; only the expenses it actually
+// stores are recorded, so idempotent retries and failed calls are free.
+fn with_rate_limit<T>(
+    owner: &Principal,
+    adds: usize,
+    insert: impl FnOnce() -> Result<T, Error>,
+) -> Result<T, Error> {
+    let settings = current_settings();
+    let window = settings
+        .rate_limit_window_secs
+        .saturating_mul(1_000_000_000);
+    let max_adds = settings.rate_limit_max_calls as usize;
+    if adds > max_adds {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Adding {} expenses at once exceeds the rate limit of {} per {} seconds",
+                adds, max_adds, settings.rate_limit_window_secs
+            ),
+        });
+    }
+    let now = time();
+    RECENT_ADDS.with(|recent| {
+        let mut recent = recent.borrow_mut();
+        let Some(recorded) = recent.get_mut(owner) else {
+            return Ok(());
+        };
+        while recorded
+            .front()
+            .is_some_and(|&at| at.saturating_add(window) <= now)
+        {
+            recorded.pop_front();
+        }
+        // The limit may have been lowered since these adds were recorded
+        while recorded.len() > max_adds {
+            recorded.pop_front();
+        }
+        if recorded.is_empty() {
+            recent.remove(owner);
+            return Ok(());
+        }
+        if recorded.len() + adds > max_adds {
+            // The oldest adds have to expire until the new ones fit
+            let last_to_expire = recorded[recorded.len() + adds - max_adds - 1];
+            return Err(Error::RateLimited {
+                retry_after: last_to_expire.saturating_add(window) - now,
+            });
+        }
+        Ok(())
+    })?;
+
+    let first_new_id = ID_COUNTER.with(|counter| *counter.borrow().get());
+    let result = insert()?;
+    let added = ID_COUNTER.with(|counter| *counter.borrow().get()) - first_new_id;
+    if added > 0 {
+        RECENT_ADDS.with(|recent| {
+            let mut recent = recent.borrow_mut();
+            let recorded = recent.entry(*owner).or_default();
+            recorded.extend(std::iter::repeat_n(now, added as usize));
+        });
+    }
+    Ok(result)
+}
+
+// Helper function to check that `owner` can store `additional` more expenses
+fn ensure_quota(owner: &Principal, additional: u64) -> Result<(), Error> {
+    let limit = current_settings().max_expenses_per_owner;
+    if owner_expense_count(owner).saturating_add(additional) > limit {
+        return Err(Error::QuotaExceeded { limit });
+    }
+    Ok(())
+}
+
+// Helper function to read how many expenses `owner` has stored
+fn owner_expense_count(owner: &Principal) -> u64 {
+    OWNER_COUNT_STORAGE
+        .with(|service| service.borrow().get(&OwnerKey(*owner)))
+        .unwrap_or(0)
+}
+
+// Helper function to move an owner's stored expense count up or down
+fn adjust_owner_count(owner: &Principal, delta: i64) {
+    let count = owner_expense_count(owner).saturating_add_signed(delta);
+    OWNER_COUNT_STORAGE.with(|service| {
+        let mut service = service.borrow_mut();
+        if count == 0 {
+            service.remove(&OwnerKey(*owner));
+        } else {
+            service.insert(OwnerKey(*owner), count);
+        }
+    });
+}
+
+// Helper function to recount every owner's expenses from `STORAGE`, so the
+// counts are correct even for data stored before they were tracked
+fn rebuild_owner_counts() {
+    let mut counts: BTreeMap<Principal, u64> = BTreeMap::new();
+    STORAGE.with(|service| {
+        for (_, expense) in service.borrow().iter() {
+            *counts.entry(expense.owner).or_insert(0) += 1;
+        }
+    });
+    OWNER_COUNT_STORAGE.with(|service| {
+        let mut service = service.borrow_mut();
+        let keys: Vec<OwnerKey> = service.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            service.remove(&key);
+        }
+        for (owner, count) in counts {
+            service.insert(OwnerKey(owner), count);
+        }
+    });
+}
+
+// Helper function to convert stored dates that were written in seconds or
+// milliseconds to nanoseconds: those of expenses, refunds, recurring
+// templates, and planned expenses.
+fn normalize_stored_dates() {
+    normalize_dates_in(&STORAGE, |expense| &mut expense.date);
+    normalize_dates_in(&REFUND_STORAGE, |refund| &mut refund.date);
+    normalize_dates_in(&RECURRING_STORAGE, |template| &mut template.next_due);
+    normalize_dates_in(&PLANNED_STORAGE, |planned| &mut planned.due_date);
+}
+
+// Helper function to convert the date `date_of` picks out of each record in
+// `map` to nanoseconds, guessing its unit from its size. Zero and nanosecond
+// dates are left alone, so running it again changes nothing.
+fn normalize_dates_in<K, V>(
+    map: &'static std::thread::LocalKey<RefCell<StableBTreeMap<K, V, Memory>>>,
+    date_of: impl Fn(&mut V) -> &mut u64,
+) where
+    K: BoundedStorable + Ord + Clone,
+    V: BoundedStorable,
+{
+    // Collect first, then write, so the map isn't borrowed mutably while it
+    // is being iterated
+    let misdated: Vec<(K, V)> = map.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter_map(|(key, mut record)| {
+                let date = *date_of(&mut record);
+                (date != 0 && date < MILLIS_DATE_THRESHOLD).then_some((key, record))
+            })
+            .collect()
+    });
+    map.with(|service| {
+        let mut service = service.borrow_mut();
+        for (key, mut record) in misdated {
+            let date = date_of(&mut record);
+            *date = if *date < SECONDS_DATE_THRESHOLD {
+                *date * 1_000_000_000
+            } else {
+                *date * 1_000_000
+            };
+            service.insert(key, record);
+        }
+    });
+}
+
+// Helper function to drop the whole history of an expense
+fn remove_history(id: u64) {
+    HISTORY_STORAGE.with(|service| {
+        let mut service = service.borrow_mut();
+        let keys: Vec<(u64, u64)> = service
+            .range((id, 0)..=(id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            service.remove(&key);
+        }
+    });
+}
+
+// Helper function to check that an expense has room for another comment
+fn ensure_comment_capacity(expense_id: u64) -> Result<(), Error> {
+    let count = COMMENT_STORAGE.with(|service| {
+        service
+            .borrow()
+            .range((expense_id, 0)..=(expense_id, u64::MAX))
+            .count()
+    });
+    if count >= MAX_COMMENTS_PER_EXPENSE {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "An expense can have at most {} comments",
+                MAX_COMMENTS_PER_EXPENSE
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Helper function to append a comment by the caller to an expense's thread
+fn append_comment(expense_id: u64, text: String) -> Comment {
+    COMMENT_STORAGE.with(|service| {
+        let mut service = service.borrow_mut();
+        let next_seq = service
+            .range((expense_id, 0)..=(expense_id, u64::MAX))
+            .last()
+            .map_or(0, |((_, seq), _)| seq + 1);
+        let comment = Comment {
+            author: caller(),
+            text,
+            created_at: time(),
+        };
+        service.insert((expense_id, next_seq), comment.clone());
+        comment
+    })
+}
+
+// Helper function to drop every comment on an expense
+fn remove_comments(id: u64) {
+    COMMENT_STORAGE.with(|service| {
+        let mut service = service.borrow_mut();
+        let keys: Vec<(u64, u64)> = service
+            .range((id, 0)..=(id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            service.remove(&key);
+        }
+    });
+}
+
+// Helper function to drop every refund recorded against an expense
+fn remove_refunds(id: u64) {
+    REFUND_STORAGE.with(|service| {
+        let mut service = service.borrow_mut();
+        let keys: Vec<(u64, u64)> = service
+            .range((id, 0)..=(id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            service.remove(&key);
+        }
+    });
+}
+
+// Helper function to check that a new amount is not below what was already
+// refunded on the expense
+fn ensure_covers_refunds(expense: &Expense, amount: i64) -> Result<(), Error> {
+    if amount < expense.refunded_total {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Amount must be at least the {} already refunded",
+                format_minor_units(expense.refunded_total)
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Helper function to tell whether an entry counts towards totals, balances,
+// and statistics. Pending and rejected entries are left out.
+fn is_approved(expense: &Expense) -> bool {
+    expense.status == ExpenseStatus::Approved
+}
+
+// Helper function to tell whether an entry counts as spending in totals and
+// statistics, i.e. is approved money going out rather than income
+fn is_spending(expense: &Expense) -> bool {
+    expense.entry_type == EntryType::Expense && is_approved(expense)
+}
+
+// Helper function to compute what an expense cost after refunds, in minor units
+fn net_amount(expense: &Expense) -> i64 {
+    expense.amount - expense.refunded_total
+}
+
+// Helper function to perform the insertion
+// Encodes the expense up front so that a record which cannot be stored is
+// reported as an error instead of trapping inside the map
+fn do_insert(expense: &Expense) -> Result<(), Error> {
+    let size = Encode!(expense)
+        .map_err(|error| Error::StorageError {
+            msg: format!("Cannot encode expense with id={}: {}", expense.id, error),
+        })?
+        .len();
+    if size > Expense::MAX_SIZE as usize {
+        return Err(Error::StorageError {
+            msg: format!(
+                "Expense with id={} is {} bytes encoded, more than the {} bytes that can be stored",
+                expense.id,
+                size,
+                Expense::MAX_SIZE
+            ),
+        });
+    }
+    let previous = STORAGE.with(|service| service.borrow_mut().insert(expense.id, expense.clone()));
+    let kind = if previous.is_some() {
+        ActivityKind::Updated
+    } else {
+        ActivityKind::Added
+    };
+    record_activity(kind, expense);
+    Ok(())
+}
+
+// Variant names are part of the Candid interface clients match on
+#[allow(clippy::enum_variant_names)]
+#[derive(candid::CandidType, Deserialize, Serialize, Debug)]
+enum Error {
+    NotFound { msg: String },
+    Unauthorized { msg: String },
+    InvalidInput { msg: String },
+    DuplicateExpense { existing_id: u64 },
+    VersionConflict { current_version: u64 },
+    StorageError { msg: String },
+    QuotaExceeded { limit: u64 },
+    RateLimited { retry_after: u64 }, // Nanoseconds until the caller may add again
+    PeriodLocked { locked_through: u64 },
+}
+
+impl Error {
+    // Prepends context to the error message, keeping the variant
+    fn prefixed(self, prefix: &str) -> Self {
+        match self {
+            Error::NotFound { msg } => Error::NotFound {
+                msg: format!("{}: {}", prefix, msg),
+            },
+            Error::Unauthorized { msg } => Error::Unauthorized {
+                msg: format!("{}: {}", prefix, msg),
+            },
+            Error::InvalidInput { msg } => Error::InvalidInput {
+                msg: format!("{}: {}", prefix, msg),
+            },
+            Error::DuplicateExpense { existing_id } => Error::DuplicateExpense { existing_id },
+            Error::VersionConflict { current_version } => {
+                Error::VersionConflict { current_version }
+            }
+            Error::StorageError { msg } => Error::StorageError {
+                msg: format!("{}: {}", prefix, msg),
+            },
+            Error::QuotaExceeded { limit } => Error::QuotaExceeded { limit },
+            Error::RateLimited { retry_after } => Error::RateLimited { retry_after },
+            Error::PeriodLocked { locked_through } => Error::PeriodLocked { locked_through },
+        }
+    }
+}
+
+// Helper method to get an expense by id
+fn _get_expense(id: &u64) -> Option<Expense> {
+    STORAGE.with(|service| service.borrow().get(id))
+}
+
+// Helper method to get all expenses belonging to an owner
+fn _get_owned_expenses(owner: &Principal) -> Vec<Expense> {
+    STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, expense)| expense.owner == *owner)
+            .map(|(_, expense)| expense)
+            .collect()
+    })
+}
+
+// Helper method to get all non-archived expenses belonging to an owner
+fn _get_active_expenses(owner: &Principal) -> Vec<Expense> {
+    _get_owned_expenses(owner)
+        .into_iter()
+        .filter(|expense| !expense.archived)
+        .collect()
+}
+
+// Helper method to put expenses in the default list order: newest date
+// first, then highest id first among expenses on the same date. List
+// endpoints without an order of their own use it, so results don't shift
+// between calls.
+fn newest_first(mut expenses: Vec<Expense>) -> Vec<Expense> {
+    expenses.sort_by_key(|expense| Reverse((expense.date, expense.id)));
+    expenses
+}
+
+// Helper method to list advisory warnings for an expense as of `now`:
+// - the amount is under `SMALL_AMOUNT_WARNING` or over `LARGE_AMOUNT_WARNING`
+// - the date is more than `OLD_DATE_WARNING_NANOS` before `now`
+// - the date is after `now`
+fn expense_warnings(expense: &Expense, now: u64) -> Vec<String> {
+    let mut warnings = Vec::new();
+    if expense.amount < SMALL_AMOUNT_WARNING {
+        warnings.push(format!(
+            "Amount {} is unusually small",
+            format_minor_units(expense.amount)
+        ));
+    }
+    if expense.amount > LARGE_AMOUNT_WARNING {
+        warnings.push(format!(
+            "Amount {} is unusually large",
+            format_minor_units(expense.amount)
+        ));
+    }
+    if now.saturating_sub(expense.date) > OLD_DATE_WARNING_NANOS {
+        warnings.push("Date is over a year old".to_string());
+    }
+    if expense.date > now {
+        warnings.push("Date is in the future".to_string());
+    }
+    warnings
+}
+
+// Helper method to find a non-archived expense of `owner` with the same
+// description and amount whose date lies within `DUPLICATE_WINDOW_NANOS`
+fn find_duplicate(owner: &Principal, payload: &ExpensePayload) -> Result<Option<u64>, Error> {
+    let amount = to_minor_units(payload.amount)?;
+    let description = payload.description.trim();
+    Ok(STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .find(|(_, expense)| {
+                expense.owner == *owner
+                    && !expense.archived
+                    && expense.date.abs_diff(payload.date) <= DUPLICATE_WINDOW_NANOS
+                    && expense.description.trim() == description
+                    && amounts_equal(from_minor_units(expense.amount), from_minor_units(amount))
+            })
+            .map(|(id, _)| id)
+    }))
+}
+
+// Helper method to check that the caller owns the expense
+fn ensure_owner(expense: &Expense) -> Result<(), Error> {
+    if expense.owner != caller() {
+        return Err(Error::Unauthorized {
+            msg: format!("Caller is not the owner of expense with id={}", expense.id),
+        });
+    }
+    Ok(())
+}
+
+// Helper method to check that the caller is a controller of the canister
+fn ensure_admin() -> Result<(), Error> {
+    if !is_controller(&caller()) {
+        return Err(Error::Unauthorized {
+            msg: "Only canister controllers can perform this action".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Helper method to check that the caller may approve or reject expenses:
+// the `approver` setting if one is set, canister controllers otherwise
+fn ensure_approver() -> Result<(), Error> {
+    let allowed = match current_settings().approver {
+        Some(approver) => caller() == approver,
+        None => is_controller(&caller()),
+    };
+    if !allowed {
+        return Err(Error::Unauthorized {
+            msg: "Only the approver can review expenses".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Helper method to load an expense that is waiting for review
+fn pending_expense_for_review(id: u64) -> Result<Expense, Error> {
+    let expense = _get_expense(&id).ok_or_else(|| Error::NotFound {
+        msg: format!("Couldn't review expense with id={}. Expense not found.", id),
+    })?;
+    if expense.archived || expense.status != ExpenseStatus::Pending {
+        return Err(Error::InvalidInput {
+            msg: format!("Expense with id={} is not pending review", id),
+        });
+    }
+    Ok(expense)
+}
+
+// Helper method to read the period lock; 0 when nothing is locked
+fn locked_through() -> u64 {
+    LOCKED_THROUGH.with(|cell| *cell.borrow().get())
+}
+
+// Helper method to store a new period lock
+fn set_locked_through(value: u64) -> Result<(), Error> {
+    LOCKED_THROUGH
+        .with(|cell| cell.borrow_mut().set(value))
+        .map(|_| ())
+        .map_err(|error| Error::StorageError {
+            msg: format!("Cannot store period lock: {:?}", error),
+        })
+}
+
+// Helper method to check that an expense dated `date` is outside every
+// locked period
+fn ensure_unlocked(date: u64) -> Result<(), Error> {
+    let locked_through = locked_through();
+    if locked_through > 0 && date <= locked_through {
+        return Err(Error::PeriodLocked { locked_through });
+    }
+    Ok(())
+}
+
+// Helper method to get a category belonging to an owner
+fn _get_owned_category(owner: &Principal, id: &u64) -> Result<Category, Error> {
+    match CATEGORY_STORAGE.with(|service| service.borrow().get(id)) {
+        Some(category) if category.owner == *owner => Ok(category),
+        Some(_) => Err(Error::Unauthorized {
+            msg: format!("Caller is not the owner of category with id={}", id),
+        }),
+        None => Err(Error::NotFound {
+            msg: format!("Category with id={} not found", id),
+        }),
+    }
+}
+
+// Helper function to check whether a category id refers to a stored category
+fn category_exists(id: u64) -> bool {
+    CATEGORY_STORAGE.with(|service| service.borrow().contains_key(&id))
+}
+
+// Helper function to check whether an expense references a category that no
+// longer exists
+fn has_missing_category(expense: &Expense) -> bool {
+    !expense.category_id.is_none_or(category_exists)
+        || expense
+            .category_splits
+            .iter()
+            .any(|split| !category_exists(split.category_id))
+}
+
+// Helper function to find the category of the first rule whose keyword
+// appears in `description`, ignoring case. Rules whose category no longer
+// exists are skipped rather than filling in an id validation would reject.
+fn matching_rule(rules: &[CategoryRule], description: &str) -> Option<u64> {
+    let description = description.to_lowercase();
+    rules
+        .iter()
+        .find(|rule| description.contains(&rule.keyword) && category_exists(rule.category_id))
+        .map(|rule| rule.category_id)
+}
+
+// Helper method to fill in the category of a payload that leaves it unset
+// from the owner's rules. Payloads with category splits are left alone.
+fn apply_category_rules(owner: &Principal, payload: &mut ExpensePayload) {
+    if payload.category_id.is_some()
+        || payload
+            .category_splits
+            .as_ref()
+            .is_some_and(|splits| !splits.is_empty())
+    {
+        return;
+    }
+    payload.category_id = matching_rule(&_get_owned_rules(owner), &payload.description);
+}
+
+// Helper method to get an owner's category rules, oldest first
+fn _get_owned_rules(owner: &Principal) -> Vec<CategoryRule> {
+    CATEGORY_RULE_STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, rule)| rule.owner == *owner)
+            .map(|(_, rule)| rule)
+            .collect()
+    })
+}
+
+// Helper method to check that an optional category reference is valid for an owner
+fn validate_category(owner: &Principal, category_id: Option<u64>) -> Result<(), Error> {
+    match category_id {
+        Some(id) => _get_owned_category(owner, &id).map(|_| ()),
+        None => Ok(()),
+    }
+}
+
+// Helper method to run every check an expense payload has to pass. Text
+// fields are trimmed in place first, so the stored values are the ones checked.
+fn validate_expense_payload(owner: &Principal, payload: &mut ExpensePayload) -> Result<(), Error> {
+    payload.description = payload.description.trim().to_string();
+    if payload.description.is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "Description must not be empty".to_string(),
+        });
+    }
+    payload.merchant = trim_optional_text(payload.merchant.take());
+    payload.note = trim_optional_text(payload.note.take());
+    payload.receipt_url = trim_optional_text(payload.receipt_url.take());
+    if let Some(url) = &payload.receipt_url {
+        validate_receipt_url(url)?;
+    }
+    payload.idempotency_key = trim_optional_text(payload.idempotency_key.take());
+    if let Some(key) = &payload.idempotency_key {
+        if key.len() > MAX_IDEMPOTENCY_KEY_LEN {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "idempotency_key must be at most {} bytes long",
+                    MAX_IDEMPOTENCY_KEY_LEN
+                ),
+            });
+        }
+    }
+    payload.amount = round_amount(payload.amount);
+    validate_amount(payload.amount)?;
+    validate_date(payload.date)?;
+    validate_category(owner, payload.category_id)?;
+    let currency = resolve_currency(payload.currency.take());
+    validate_allowed_currency(&currency)?;
+    payload.currency = Some(currency);
+    validate_optional_text("merchant", &payload.merchant, MAX_MERCHANT_LEN)?;
+    validate_optional_text("note", &payload.note, MAX_NOTE_LEN)?;
+    if let Some(splits) = payload.splits.as_mut() {
+        validate_splits(splits, payload.amount)?;
+    }
+    if let Some(splits) = &payload.category_splits {
+        validate_category_splits(owner, splits, payload.amount)?;
+    }
+
+    // Measure the largest form the record can take over its lifetime, so
+    // neither the insert nor a later update can overflow the storable bound.
+    let mut largest = expense_from_payload(u64::MAX, *owner, payload.clone(), u64::MAX)?;
+    largest.tags = vec!["x".repeat(MAX_TAG_LEN); MAX_TAGS];
+    largest.updated_at = Some(u64::MAX);
+    largest.archived = true;
+    largest.archived_at = Some(u64::MAX);
+    largest.reimbursed_at = Some(u64::MAX);
+    validate_encoded_size(&largest)
+}
+
+// Helper method to check that an expense fits within `Expense::MAX_SIZE` once encoded
+fn validate_encoded_size(expense: &Expense) -> Result<(), Error> {
+    let size = Encode!(expense)
+        .map_err(|error| Error::InvalidInput {
+            msg: format!("Expense cannot be encoded: {}", error),
+        })?
+        .len();
+    if size > Expense::MAX_SIZE as usize {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Expense is too large to store: encoded size is {} bytes, but at most {} bytes are allowed. Shorten the description, merchant, note, receipt URL, splits, or category splits.",
+                size,
+                Expense::MAX_SIZE
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Helper method to check that splits name distinct participants and that
+// their shares add up to the expense amount (see `amounts_equal`).
+// Participant names are trimmed in place.
+fn validate_splits(splits: &mut [SplitPayload], amount: f64) -> Result<(), Error> {
+    if splits.len() > MAX_SPLITS {
+        return Err(Error::InvalidInput {
+            msg: format!("An expense can have at most {} splits", MAX_SPLITS),
+        });
+    }
+    let mut participants = Vec::with_capacity(splits.len());
+    let mut total: i64 = 0;
+    for split in splits.iter_mut() {
+        split.participant = split.participant.trim().to_string();
+        if split.participant.is_empty() || split.participant.chars().count() > MAX_PARTICIPANT_LEN {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "Participant names must be between 1 and {} characters long",
+                    MAX_PARTICIPANT_LEN
+                ),
+            });
+        }
+        let name = split.participant.to_lowercase();
+        if participants.contains(&name) {
+            return Err(Error::InvalidInput {
+                msg: format!("Participant {:?} appears more than once", split.participant),
+            });
+        }
+        participants.push(name);
+        let share = to_minor_units(split.share)?;
+        if share <= 0 {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "Share for {:?} must be positive, got {}",
+                    split.participant, split.share
+                ),
+            });
+        }
+        total = total.saturating_add(share);
+    }
+    let amount = to_minor_units(amount)?;
+    if !splits.is_empty() && !amounts_equal(from_minor_units(total), from_minor_units(amount)) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Shares add up to {} but the expense amount is {}",
+                format_minor_units(total),
+                format_minor_units(amount)
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Helper method to check that category splits name distinct categories of
+// the owner, each with a positive amount, and add up to the expense
+// amount (see `amounts_equal`)
+fn validate_category_splits(
+    owner: &Principal,
+    splits: &[CategorySplitPayload],
+    amount: f64,
+) -> Result<(), Error> {
+    if splits.len() > MAX_CATEGORY_SPLITS {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "An expense can have at most {} category splits",
+                MAX_CATEGORY_SPLITS
+            ),
+        });
+    }
+    let mut categories = BTreeSet::new();
+    let mut total: i64 = 0;
+    for split in splits {
+        _get_owned_category(owner, &split.category_id)?;
+        if !categories.insert(split.category_id) {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "Category with id={} appears more than once in category_splits",
+                    split.category_id
+                ),
+            });
+        }
+        let part = to_minor_units(split.amount)?;
+        if part <= 0 {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "Split amount for category with id={} must be positive, got {}",
+                    split.category_id, split.amount
+                ),
+            });
+        }
+        total = total.saturating_add(part);
+    }
+    let amount = to_minor_units(amount)?;
+    if !splits.is_empty() && !amounts_equal(from_minor_units(total), from_minor_units(amount)) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Category splits add up to {} but the expense amount is {}",
+                format_minor_units(total),
+                format_minor_units(amount)
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Helper function to multiply an amount in minor units by `factor`, rounding
+// to the nearest minor unit
+fn scale_minor_units(value: i64, factor: f64) -> Result<i64, Error> {
+    let scaled = (value as f64 * factor).round();
+    if !scaled.is_finite() || scaled.abs() >= i64::MAX as f64 {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Scaling {} by {} is out of range",
+                format_minor_units(value),
+                factor
+            ),
+        });
+    }
+    Ok(scaled as i64)
+}
+
+// Helper function to scale the parts of an amount so that they still add up
+// to the already scaled `total`. Rounding differences go to the last part,
+// and every part has to stay positive.
+fn scale_parts(parts: &[i64], factor: f64, total: i64) -> Result<Vec<i64>, Error> {
+    let mut scaled = parts
+        .iter()
+        .map(|&part| scale_minor_units(part, factor))
+        .collect::<Result<Vec<i64>, Error>>()?;
+    let difference = total - scaled.iter().sum::<i64>();
+    if let Some(last) = scaled.last_mut() {
+        *last += difference;
+    }
+    if scaled.iter().any(|&part| part <= 0) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Scaling by {} would leave a share, category split, or refund at zero",
+                factor
+            ),
+        });
+    }
+    Ok(scaled)
+}
+
+// Helper function to convert validated split payloads into stored splits
+fn splits_from_payload(splits: Option<Vec<SplitPayload>>) -> Result<Vec<Split>, Error> {
+    splits
+        .unwrap_or_default()
+        .into_iter()
+        .map(|split| {
+            Ok(Split {
+                participant: split.participant,
+                share: to_minor_units(split.share)?,
+            })
+        })
+        .collect()
+}
+
+// Helper function to convert validated category split payloads into stored ones
+fn category_splits_from_payload(
+    splits: Option<Vec<CategorySplitPayload>>,
+) -> Result<Vec<CategorySplit>, Error> {
+    splits
+        .unwrap_or_default()
+        .into_iter()
+        .map(|split| {
+            Ok(CategorySplit {
+                category_id: split.category_id,
+                amount: to_minor_units(split.amount)?,
+            })
+        })
+        .collect()
+}
+
+// Helper function to list which category each part of an expense's amount,
+// net of refunds, belongs to: its category splits when it has any, otherwise
+// the whole amount under `category_id`. Refunds are taken from the splits in
+// proportion to their size, the last split absorbing the rounding.
+fn category_parts(expense: &Expense) -> Vec<(Option<u64>, i64)> {
+    if expense.category_splits.is_empty() {
+        return vec![(expense.category_id, net_amount(expense))];
+    }
+    let mut unassigned = expense.refunded_total;
+    let mut parts: Vec<(Option<u64>, i64)> = expense
+        .category_splits
+        .iter()
+        .map(|split| {
+            let refunded = (i128::from(split.amount) * i128::from(expense.refunded_total)
+                / i128::from(expense.amount)) as i64;
+            unassigned -= refunded;
+            (Some(split.category_id), split.amount - refunded)
+        })
+        .collect();
+    if let Some((_, last)) = parts.last_mut() {
+        *last -= unassigned;
+    }
+    parts
+}
+
+// Helper method to check that a receipt link uses a supported scheme and
+// looks like a URL
+fn validate_receipt_url(url: &str) -> Result<(), Error> {
+    if url.chars().count() > MAX_RECEIPT_URL_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "receipt_url must be at most {} characters long",
+                MAX_RECEIPT_URL_LEN
+            ),
+        });
+    }
+    let rest = RECEIPT_URL_SCHEMES
+        .iter()
+        .find_map(|scheme| url.strip_prefix(scheme));
+    match rest {
+        Some(rest) if !rest.is_empty() && !rest.chars().any(char::is_whitespace) => Ok(()),
+        _ => Err(Error::InvalidInput {
+            msg: format!(
+                "receipt_url must be an http://, https://, or ipfs:// link, got {:?}",
+                url
+            ),
+        }),
+    }
+}
+
+// Helper method to trim an optional free-text field, dropping it when blank
+fn trim_optional_text(value: Option<String>) -> Option<String> {
+    value
+        .map(|text| text.trim().to_string())
+        .filter(|text| !text.is_empty())
+}
+
+// Helper method to check the length of an optional free-text field
+fn validate_optional_text(
+    field: &str,
+    value: &Option<String>,
+    max_len: usize,
+) -> Result<(), Error> {
+    match value {
+        Some(text) if text.chars().count() > max_len => Err(Error::InvalidInput {
+            msg: format!("{} must be at most {} characters long", field, max_len),
+        }),
+        _ => Ok(()),
+    }
+}
+
+// Helper method to normalize a tag to lowercase and check its contents
+fn normalize_tag(tag: &str) -> Result<String, Error> {
+    let tag = tag.trim().to_lowercase();
+    if tag.is_empty() || tag.chars().count() > MAX_TAG_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!("Tag must be between 1 and {} characters long", MAX_TAG_LEN),
+        });
+    }
+    if !tag
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Tag {:?} may only contain letters, digits, '-' and '_'",
+                tag
+            ),
+        });
+    }
+    Ok(tag)
+}
+
+// Helper method to total spending, excluding income, per calendar day in
+// the given UTC offset, oldest first. Days without expenses are left out.
+fn totals_by_day(expenses: impl Iterator<Item = Expense>, offset_minutes: i32) -> Vec<DailyTotal> {
+    let mut days: BTreeMap<u64, (i64, u64)> = BTreeMap::new();
+    for expense in expenses.filter(is_spending) {
+        let local_day = shift_to_offset(expense.date, offset_minutes) / NANOS_PER_DAY;
+        let entry = days.entry(local_day).or_insert((0, 0));
+        entry.0 += net_amount(&expense);
+        entry.1 += 1;
+    }
+    days.into_iter()
+        .map(|(day_number, (total, count))| {
+            let local_midnight = day_number * NANOS_PER_DAY;
+            let (_, _, day) = civil_date_from_nanos(local_midnight);
+            DailyTotal {
+                day,
+                date: shift_to_offset(local_midnight, -offset_minutes),
+                total: from_minor_units(total),
+                count,
+            }
+        })
+        .collect()
+}
+
+// Helper method to total spending, excluding income, per category. Returns
+// the total in minor units and the number of expenses for each category id,
+// with `None` for uncategorized expenses. A split expense adds each part to
+// its own category and counts once in each of them.
+fn totals_by_category(
+    expenses: impl Iterator<Item = Expense>,
+) -> BTreeMap<Option<u64>, (i64, u64)> {
+    let mut totals: BTreeMap<Option<u64>, (i64, u64)> = BTreeMap::new();
+    for expense in expenses.filter(is_spending) {
+        for (category_id, amount) in category_parts(&expense) {
+            let entry = totals.entry(category_id).or_insert((0, 0));
+            entry.0 += amount;
+            entry.1 += 1;
+        }
+    }
+    totals
+}
+
+// Helper method to look up the name shown for a category id in reports
+fn category_display_name(category_id: Option<u64>) -> String {
+    category_id
+        .and_then(|id| CATEGORY_STORAGE.with(|service| service.borrow().get(&id)))
+        .map_or("Uncategorized".to_string(), |category| category.name)
+}
+
+// Helper method to sum spending, excluding income, within an inclusive date
+// range. Returns the total in minor units and the number of expenses counted.
+fn sum_spending_in_range(
+    expenses: &[Expense],
+    start_date: u64,
+    end_date: u64,
+) -> Result<(i64, u64), Error> {
+    if start_date > end_date {
+        return Err(Error::InvalidInput {
+            msg: "start_date must not be after end_date".to_string(),
+        });
+    }
+    Ok(expenses
+        .iter()
+        .filter(|expense| is_spending(expense))
+        .filter(|expense| expense.date >= start_date && expense.date <= end_date)
+        .fold((0, 0), |(total, count), expense| {
+            (total + net_amount(expense), count + 1)
+        }))
+}
+
+// Helper method to check that an amount is positive and no larger than the
+// `max_amount` setting. Returns the amount in minor units.
+fn validate_amount(amount: f64) -> Result<i64, Error> {
+    let minor_units = to_minor_units(amount)?;
+    if minor_units <= 0 {
+        return Err(Error::InvalidInput {
+            msg: format!("Amount must be greater than zero, got {}", amount),
+        });
+    }
+    let max_amount = current_settings().max_amount;
+    if amount > max_amount {
+        return Err(Error::InvalidInput {
+            msg: format!("Amount {} exceeds the maximum of {}", amount, max_amount),
+        });
+    }
+    Ok(minor_units)
+}
+
+// Helper method to check that a date is a plausible nanosecond timestamp.
+// Like `ic_cdk::api::time()`, dates are nanoseconds since the Unix epoch.
+// Future dates are only accepted when the `allow_future_dates` setting is on.
+fn validate_date(date: u64) -> Result<(), Error> {
+    if date == 0 {
+        return Err(Error::InvalidInput {
+            msg: "Date must be a non-zero timestamp".to_string(),
+        });
+    }
+    let latest_allowed = time().saturating_add(NANOS_PER_DAY);
+    if date > latest_allowed && !current_settings().allow_future_dates {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Date {} is more than 24 hours in the future (latest allowed is {})",
+                date, latest_allowed
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Helper function to convert an incoming date from the `date_unit` setting
+// to nanoseconds since the epoch
+fn normalize_date(date: u64) -> Result<u64, Error> {
+    let (factor, unit) = match current_settings().date_unit {
+        DateUnit::Nanos => return Ok(date),
+        DateUnit::Millis => (1_000_000, "milliseconds"),
+        DateUnit::Seconds => (1_000_000_000, "seconds"),
+    };
+    date.checked_mul(factor).ok_or_else(|| Error::InvalidInput {
+        msg: format!("Date {} is too large for a timestamp in {}", date, unit),
+    })
+}
+
+// Helper method to check that a currency code is three uppercase ASCII letters
+fn validate_currency_code(code: &str) -> Result<(), Error> {
+    if code.len() != 3 || !code.bytes().all(|byte| byte.is_ascii_uppercase()) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Currency code must be three uppercase letters (ISO 4217), got {:?}",
+                code
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Helper method to fill in the `base_currency` setting for a payload that
+// leaves its currency out, as clients from before multi-currency do
+fn resolve_currency(currency: Option<String>) -> String {
+    match currency.map(|code| code.trim().to_string()) {
+        Some(code) if !code.is_empty() => code,
+        _ => current_settings().base_currency,
+    }
+}
+
+// Helper method to check that a currency code is well-formed and, when an
+// allow-list has been configured, on it
+fn validate_allowed_currency(code: &str) -> Result<(), Error> {
+    validate_currency_code(code)?;
+    let allowed = ALLOWED_CURRENCY_STORAGE.with(|service| {
+        let service = service.borrow();
+        service.is_empty() || service.contains_key(&CurrencyCode(code.to_string()))
+    });
+    if !allowed {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Currency {} is not allowed; see list_allowed_currencies",
+                code
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Helper function to check a UTC offset in minutes, defaulting to UTC itself.
+// Real-world offsets range from UTC-12:00 to UTC+14:00.
+fn validate_utc_offset(utc_offset_minutes: Option<i32>) -> Result<i32, Error> {
+    let offset = utc_offset_minutes.unwrap_or(0);
+    if !(-MAX_UTC_OFFSET_MINUTES..=MAX_UTC_OFFSET_MINUTES).contains(&offset) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "utc_offset_minutes must be between -{} and {}, got {}",
+                MAX_UTC_OFFSET_MINUTES, MAX_UTC_OFFSET_MINUTES, offset
+            ),
+        });
+    }
+    Ok(offset)
+}
+
+// Helper function to move a UTC timestamp by an offset in minutes, so that
+// calendar helpers see the local wall-clock time
+fn shift_to_offset(nanos: u64, offset_minutes: i32) -> u64 {
+    let shift = offset_minutes.unsigned_abs() as u64 * 60 * 1_000_000_000;
+    if offset_minutes >= 0 {
+        nanos.saturating_add(shift)
+    } else {
+        nanos.saturating_sub(shift)
+    }
+}
+
+// Helper function to convert a nanosecond timestamp into a UTC (year, month, day).
+// Uses the days-to-civil algorithm from Howard Hinnant's date library.
+fn civil_date_from_nanos(nanos: u64) -> (u32, u32, u32) {
+    let days = (nanos / NANOS_PER_DAY) as i64;
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    } as u32;
+    let year = (year_of_era + era * 400 + i64::from(month <= 2)) as u32;
+    (year, month, day)
+}
+
+// Helper method to get the amounts of the caller's active expenses, smallest
+// first. There is no distribution to describe without any.
+fn sorted_amounts() -> Result<Vec<i64>, Error> {
+    let mut amounts: Vec<i64> = _get_active_expenses(&caller())
+        .iter()
+        .filter(|expense| is_spending(expense))
+        .map(net_amount)
+        .collect();
+    if amounts.is_empty() {
+        return Err(Error::NotFound {
+            msg: "No expenses to compute percentiles from".to_string(),
+        });
+    }
+    amounts.sort_unstable();
+    Ok(amounts)
+}
+
+// Helper function to find the `p`-th percentile of non-empty sorted amounts.
+// The rank `p / 100 * (n - 1)` usually falls between two amounts, and the
+// result lies on the straight line between them.
+fn percentile(sorted: &[i64], p: f64) -> f64 {
+    let rank = p / 100.0 * (sorted.len() - 1) as f64;
+    let lower = rank.floor() as usize;
+    let upper = rank.ceil() as usize;
+    let low = from_minor_units(sorted[lower]);
+    let high = from_minor_units(sorted[upper]);
+    low + (high - low) * (rank - lower as f64)
+}
+
+// Helper method to get the caller's active expenses dated within a UTC
+// calendar year, oldest first
+fn expenses_in_year(year: u32) -> Result<Vec<Expense>, Error> {
+    if !(MIN_YEAR..=MAX_YEAR).contains(&year) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Year must be between {} and {}, got {}",
+                MIN_YEAR, MAX_YEAR, year
+            ),
+        });
+    }
+    let mut expenses: Vec<Expense> = _get_active_expenses(&caller())
+        .into_iter()
+        .filter(|expense| civil_date_from_nanos(expense.date).0 == year)
+        .collect();
+    expenses.sort_by_key(|expense| (expense.date, expense.id));
+    Ok(expenses)
+}
+
+// Helper function to find the UTC day of the week of a nanosecond timestamp,
+// with 0 for Monday. The epoch, 1970-01-01, was a Thursday.
+fn weekday_from_nanos(nanos: u64) -> u8 {
+    ((nanos / NANOS_PER_DAY + 3) % 7) as u8
+}
+
+// Helper function to decode a stored record. Candid only tolerates missing
+// fields when they are `opt`, so a record written before a non-optional field
+// was added would fail to decode; such fields are taken from `T::default()`.
+// Amounts were stored as decimal `float64` before they moved to minor units,
+// so a `float64` where an `int64` is expected is converted to cents.
+fn decode_with_defaults<T>(bytes: &[u8]) -> T
+where
+    T: candid::CandidType + serde::de::DeserializeOwned + Default,
+{
+    try_decode_with_defaults(bytes).expect("Cannot decode stored record")
+}
+
+// Same as `decode_with_defaults`, but reports malformed bytes as an error
+fn try_decode_with_defaults<T>(bytes: &[u8]) -> Result<T, String>
+where
+    T: candid::CandidType + serde::de::DeserializeOwned + Default,
+{
+    if let Ok(value) = Decode!(bytes, T) {
+        return Ok(value);
+    }
+    let stored = IDLArgs::from_bytes(bytes).map_err(|error| error.to_string())?;
+    let defaults = IDLArgs::from_bytes(&Encode!(&T::default()).unwrap()).unwrap();
+    let merged = match (
+        stored.args.into_iter().next(),
+        defaults.args.into_iter().next(),
+    ) {
+        (Some(IDLValue::Record(mut fields)), Some(IDLValue::Record(default_fields))) => {
+            for field in default_fields {
+                match fields.iter_mut().find(|existing| existing.id == field.id) {
+                    Some(existing) => {
+                        if let (IDLValue::Float64(value), IDLValue::Int64(_)) =
+                            (&existing.val, &field.val)
+                        {
+                            existing.val = IDLValue::Int64(legacy_minor_units(*value));
+                        }
+                    }
+                    None => fields.push(field),
+                }
+            }
+            fields.sort_by_key(|field| field.id.get_id());
+            IDLValue::Record(fields)
+        }
+        _ => return Err("Value is not a Candid record".to_string()),
+    };
+    let bytes = IDLArgs::new(&[merged])
+        .to_bytes()
+        .map_err(|error| error.to_string())?;
+    Decode!(&bytes, T).map_err(|error| error.to_string())
+}
+
+// Helper function to round an incoming amount half-up to the `decimal_places`
+// setting, so float noise like 19.999999 is stored as 20.00. The value is
+// nudged by a relative 1e-12 first: 2.345 is held in binary as
+// 2.34499999..., and should still round up as written.
+fn round_amount(value: f64) -> f64 {
+    let factor = 10f64.powi(i32::from(current_settings().decimal_places));
+    let scaled = value * factor;
+    (scaled + scaled.abs() * 1e-12).round() / factor
+}
+
+// Helper function to convert a decimal amount into minor units (cents)
+fn to_minor_units(value: f64) -> Result<i64, Error> {
+    if !value.is_finite() {
+        return Err(Error::InvalidInput {
+            msg: format!("Amount must be a finite number, got {}", value),
+        });
+    }
+    let scaled = value * 100.0;
+    let rounded = scaled.round();
+    if (scaled - rounded).abs() > 1e-6 {
+        return Err(Error::InvalidInput {
+            msg: format!("Amount {} has more than two decimal places", value),
+        });
+    }
+    if rounded.abs() >= i64::MAX as f64 {
+        return Err(Error::InvalidInput {
+            msg: format!("Amount {} is out of range", value),
+        });
+    }
+    Ok(rounded as i64)
+}
+
+// Helper function to compare two expenses by a single field, ascending
+fn compare_by_field(a: &Expense, b: &Expense, field: SortField) -> Ordering {
+    match field {
+        SortField::Amount => a.amount.cmp(&b.amount),
+        SortField::Date => a.date.cmp(&b.date),
+        SortField::CreatedAt => a.created_at.cmp(&b.created_at),
+        SortField::Category => a.category_id.cmp(&b.category_id),
+    }
+}
+
+// Helper function to flip an ascending ordering when descending order is requested
+fn apply_sort_order(ordering: Ordering, order: SortOrder) -> Ordering {
+    match order {
+        SortOrder::Asc => ordering,
+        SortOrder::Desc => ordering.reverse(),
+    }
+}
+
+// Helper function to render minor units (cents) as an exact decimal string, e.g. "12.30"
+fn format_minor_units(value: i64) -> String {
+    let sign = if value < 0 { "-" } else { "" };
+    let cents = value.unsigned_abs();
+    format!("{}{}.{:02}", sign, cents / 100, cents % 100)
+}
+
+// Helper function to render minor units for display in a currency, e.g.
+// "$1,234.56" or "Rp1.235". Currencies without cents are rounded half up.
+// Unknown currencies fall back to "CHF 1234.56".
+fn format_amount(amount: i64, currency: &str) -> String {
+    let Some(&(_, symbol, shows_cents, thousands, decimal)) =
+        CURRENCY_FORMATS.iter().find(|(code, ..)| *code == currency)
+    else {
+        return format!("{} {}", currency, format_minor_units(amount));
+    };
+    let sign = if amount < 0 { "-" } else { "" };
+    let cents = amount.unsigned_abs();
+    let whole = if shows_cents {
+        cents / 100
+    } else {
+        (cents + 50) / 100
+    };
+    let digits = whole.to_string();
+    let mut grouped = String::with_capacity(digits.len() + digits.len() / 3);
+    for (index, digit) in digits.chars().enumerate() {
+        if index > 0 && (digits.len() - index).is_multiple_of(3) {
+            grouped.push(thousands);
+        }
+        grouped.push(digit);
+    }
+    if shows_cents {
+        format!("{}{}{}{}{:02}", sign, symbol, grouped, decimal, cents % 100)
+    } else {
+        format!("{}{}{}", sign, symbol, grouped)
+    }
+}
+
+// Helper function to quote a CSV field when it contains separators or quotes (RFC 4180)
+fn escape_csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// Helper function to convert a stored legacy decimal amount into minor units.
+// Unlike `to_minor_units` it never fails: the value was accepted when it was
+// written, so extra decimals are rounded away and non-finite values become 0.
+fn legacy_minor_units(value: f64) -> i64 {
+    if value.is_finite() {
+        (value * 100.0).round() as i64
+    } else {
+        0
+    }
+}
+
+// Helper function to tell whether two amounts are equal within the
+// `amount_epsilon` setting. Use it wherever amounts are required to match,
+// e.g. split shares against the expense amount.
+fn amounts_equal(a: f64, b: f64) -> bool {
+    (a - b).abs() <= current_settings().amount_epsilon
+}
+
+// Helper function to convert minor units (cents) back into a decimal amount
+fn from_minor_units(value: i64) -> f64 {
+    value as f64 / 100.0
+}
+
+// Export candid for the canister
+ic_cdk::export_candid!();
+
+#[cfg(test)]
+use tests::{caller, is_controller, time};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell as TestCell;
+
+    const NOW: u64 = 1_700_000_000_000_000_000;
+
+    thread_local! {
+        static CALLER: TestCell<Principal> = const { TestCell::new(Principal::from_slice(&[1])) };
+        static CLOCK: TestCell<u64> = const { TestCell::new(NOW) };
+    }
+
+    // Stand-ins for the system API, which only exists inside a canister.
+    // Each test runs on its own thread, so it starts with empty storage.
+    pub(super) fn caller() -> Principal {
+        CALLER.with(TestCell::get)
+    }
+
+    pub(super) fn time() -> u64 {
+        CLOCK.with(TestCell::get)
+    }
+
+    // The default test caller is the only controller
+    pub(super) fn is_controller(principal: &Principal) -> bool {
+        *principal == Principal::from_slice(&[1])
+    }
+
+    fn advance_clock(nanos: u64) {
+        CLOCK.with(|clock| clock.set(clock.get() + nanos));
+    }
+
+    fn payload(description: &str, amount: f64) -> ExpensePayload {
+        ExpensePayload {
+            description: description.to_string(),
+            amount,
+            date: NOW - NANOS_PER_DAY,
+            ..Default::default()
+        }
+    }
+
+    fn change_settings(change: impl FnOnce(&mut Settings)) {
+        let mut settings = current_settings();
+        change(&mut settings);
+        SETTINGS
+            .with(|cell| cell.borrow_mut().set(settings))
+            .unwrap();
+    }
+
+    // Approves the expense with the test caller acting as the approver
+    fn approve(expense: &Expense) {
+        change_settings(|settings| settings.approver = Some(caller()));
+        approve_expense(expense.id).unwrap();
+    }
+
+    fn stored_expense(id: u64, description: &str, amount: i64) -> Expense {
+        Expense {
+            id,
+            owner: caller(),
+            description: description.to_string(),
+            amount,
+            date: NOW - NANOS_PER_DAY,
+            currency: "USD".to_string(),
+            created_at: NOW,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn stable_structures_survive_reinitialization() {
+        let memory = DefaultMemoryImpl::default();
+        {
+            let manager = MemoryManager::init(memory.clone());
+            let mut storage: StableBTreeMap<u64, Expense, Memory> =
+                StableBTreeMap::init(manager.get(STORAGE_MEMORY_ID));
+            let mut counter = IdCell::init(manager.get(ID_COUNTER_MEMORY_ID), 0).unwrap();
+            for id in 0..3 {
+                storage.insert(id, stored_expense(id, &format!("Expense {}", id), 100));
+            }
+            counter.set(3).unwrap();
+        }
+
+        // An upgrade starts with a fresh heap and re-initializes every
+        // structure over the same stable memory
+        let manager = MemoryManager::init(memory);
+        let storage: StableBTreeMap<u64, Expense, Memory> =
+            StableBTreeMap::init(manager.get(STORAGE_MEMORY_ID));
+        let counter = IdCell::init(manager.get(ID_COUNTER_MEMORY_ID), 0).unwrap();
+        assert_eq!(*counter.get(), 3);
+        let ids: Vec<u64> = storage.iter().map(|(id, _)| id).collect();
+        assert_eq!(ids, vec![0, 1, 2]);
+        assert_eq!(storage.get(&1).unwrap().description, "Expense 1");
+    }
+
+    #[test]
+    fn post_upgrade_moves_counter_past_stored_ids() {
+        STORAGE.with(|service| {
+            let mut service = service.borrow_mut();
+            for id in [0, 1, 5] {
+                service.insert(id, stored_expense(id, "Rent", 50_000));
+            }
+        });
+        post_upgrade();
+        assert_eq!(ID_COUNTER.with(|counter| *counter.borrow().get()), 6);
+        assert_eq!(owner_expense_count(&caller()), 3);
+
+        let expense = add_expense(payload("Groceries", 12.5)).unwrap();
+        assert_eq!(expense.id, 6);
+        assert_eq!(_get_expense(&5).unwrap().description, "Rent");
+    }
+
+    #[test]
+    fn oversized_expense_is_rejected_without_trapping() {
+        let fits = |len: usize| {
+            validate_expense_payload(&caller(), &mut payload(&"x".repeat(len), 10.0)).is_ok()
+        };
+        let longest = (1..Expense::MAX_SIZE as usize)
+            .take_while(|&len| fits(len))
+            .last()
+            .unwrap();
+        assert!(longest >= 300, "{}", longest);
+
+        let expense = add_expense(payload(&"x".repeat(longest), 10.0)).unwrap();
+        assert_eq!(expense.description.len(), longest);
+        match add_expense(payload(&"y".repeat(longest + 1), 10.0)) {
+            Err(Error::InvalidInput { msg }) => {
+                assert!(msg.contains("encoded size"), "{}", msg);
+                assert!(msg.contains(&Expense::MAX_SIZE.to_string()), "{}", msg);
+            }
+            other => panic!("expected InvalidInput, got {:?}", other.map(|e| e.id)),
+        }
+        assert_eq!(STORAGE.with(|service| service.borrow().len()), 1);
+
+        // The largest accepted record still has room for every tag it may get
+        for index in 0..MAX_TAGS {
+            let tag = format!("{}{}", index, "t".repeat(MAX_TAG_LEN - 1));
+            add_tag_to_expense(expense.id, tag).unwrap();
+        }
+    }
+
+    // Splits one CSV record into its fields, undoing RFC 4180 quoting
+    fn parse_csv_record(line: &str) -> Vec<String> {
+        let mut fields = vec![String::new()];
+        let mut quoted = false;
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            match (c, quoted) {
+                ('"', true) if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    fields.last_mut().unwrap().push('"');
+                }
+                ('"', _) => quoted = !quoted,
+                (',', false) => fields.push(String::new()),
+                _ => fields.last_mut().unwrap().push(c),
+            }
+        }
+        fields
+    }
+
+    #[test]
+    fn csv_export_round_trips_commas_and_quotes() {
+        let tricky = r#"Dinner, "Chez Marie""#;
+        let mut later = payload(tricky, 42.1);
+        later.date = NOW - NANOS_PER_DAY / 2;
+        add_expense(later).unwrap();
+        add_expense(payload("Coffee", 3.0)).unwrap();
+
+        let csv = export_expenses_csv();
+        let lines: Vec<&str> = csv.split_terminator("\r\n").collect();
+        assert_eq!(lines[0], "id,description,amount,date,created_at,updated_at");
+        assert_eq!(lines.len(), 3);
+        assert_eq!(
+            lines[2],
+            format!(
+                "0,{},42.10,{},{},",
+                escape_csv_field(tricky),
+                NOW - NANOS_PER_DAY / 2,
+                NOW
+            )
+        );
+
+        // Rows are ordered by date, so the earlier coffee comes first
+        let coffee = parse_csv_record(lines[1]);
+        let dinner = parse_csv_record(lines[2]);
+        assert_eq!(coffee[1], "Coffee");
+        assert_eq!(dinner.len(), 6);
+        assert_eq!(dinner[1], tricky);
+        assert_eq!(dinner[2], "42.10");
+    }
+
+    #[test]
+    fn csv_fields_are_quoted_only_when_needed() {
+        assert_eq!(escape_csv_field("Lunch"), "Lunch");
+        assert_eq!(escape_csv_field("a,b"), "\"a,b\"");
+        assert_eq!(escape_csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(escape_csv_field("two\nlines"), "\"two\nlines\"");
+    }
+
+    // `Expense` as stored by the first release, with a decimal amount and
+    // without an owner
+    #[derive(candid::CandidType)]
+    struct BaselineExpense {
+        id: u64,
+        description: String,
+        amount: f64,
+        date: u64,
+        created_at: u64,
+        updated_at: Option<u64>,
+    }
+
+    #[test]
+    fn decodes_baseline_expense_with_float_amount() {
+        let stored = BaselineExpense {
+            id: 7,
+            description: "Coffee".to_string(),
+            amount: 12.34,
+            date: 1_700_000_000_000_000_000,
+            created_at: 1_700_000_000_000_000_001,
+            updated_at: Some(1_700_000_000_000_000_002),
+        };
+        let expense = Expense::from_bytes(Cow::Owned(Encode!(&stored).unwrap()));
+        assert_eq!(expense.id, 7);
+        assert_eq!(expense.description, "Coffee");
+        assert_eq!(expense.amount, 1234);
+        assert_eq!(expense.date, stored.date);
+        assert_eq!(expense.updated_at, stored.updated_at);
+        assert_eq!(expense.owner, Principal::anonymous());
+        assert_eq!(expense.currency, "");
+        assert!(expense.status == ExpenseStatus::Approved);
+    }
+
+    #[test]
+    fn legacy_amounts_round_to_cents() {
+        assert_eq!(legacy_minor_units(0.1 + 0.2), 30);
+        assert_eq!(legacy_minor_units(19.999), 2000);
+        assert_eq!(legacy_minor_units(f64::NAN), 0);
+        assert_eq!(legacy_minor_units(f64::INFINITY), 0);
+    }
+
+    #[test]
+    fn descriptions_and_optional_text_are_trimmed() {
+        assert!(matches!(
+            add_expense(payload("   ", 5.0)),
+            Err(Error::InvalidInput { .. })
+        ));
+        assert!(matches!(
+            add_expense(payload("\t\n", 5.0)),
+            Err(Error::InvalidInput { .. })
+        ));
+
+        let mut padded = payload("  Bus ticket \n", 2.5);
+        padded.merchant = Some("  City Transit ".to_string());
+        padded.note = Some("   ".to_string());
+        let expense = add_expense(padded).unwrap();
+        assert_eq!(expense.description, "Bus ticket");
+        assert_eq!(expense.merchant.as_deref(), Some("City Transit"));
+        assert_eq!(expense.note, None);
+
+        assert!(matches!(
+            update_expense(expense.id, expense.version, payload("  ", 2.5), None),
+            Err(Error::InvalidInput { .. })
+        ));
+        let updated =
+            update_expense(expense.id, expense.version, payload(" Tram ", 2.5), None).unwrap();
+        assert_eq!(updated.description, "Tram");
+    }
+
+    #[test]
+    fn totals_avoid_float_pitfalls() {
+        for amount in [0.1, 0.2] {
+            approve(&add_expense(payload(&format!("Candy {}", amount), amount)).unwrap());
+        }
+        assert_eq!(calculate_total_expenses_formatted(), "0.30");
+        assert_eq!(calculate_total_expenses(), 0.3);
+
+        assert_eq!(to_minor_units(0.1 + 0.2).unwrap(), 30);
+        assert_eq!(to_minor_units(29.999999996).unwrap(), 3000);
+        let tenths: i64 = (0..10).map(|_| to_minor_units(0.1).unwrap()).sum();
+        assert_eq!(format_minor_units(tenths), "1.00");
+    }
+
+    #[test]
+    fn minor_units_convert_and_format_exactly() {
+        assert_eq!(to_minor_units(12.34).unwrap(), 1234);
+        assert_eq!(to_minor_units(-0.05).unwrap(), -5);
+        assert!(to_minor_units(1.234).is_err());
+        assert_eq!(from_minor_units(1234), 12.34);
+        assert_eq!(format_minor_units(0), "0.00");
+        assert_eq!(format_minor_units(5), "0.05");
+        assert_eq!(format_minor_units(3000), "30.00");
+        assert_eq!(format_minor_units(-1234), "-12.34");
+        assert_eq!(format_minor_units(i64::MIN), "-92233720368547758.08");
+    }
+
+    #[test]
+    fn invalid_and_excessive_amounts_are_rejected() {
+        for amount in [
+            f64::NAN,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+            -5.0,
+            0.0,
+            1_000_000_000.01,
+            f64::MAX,
+        ] {
+            match add_expense(payload("Bad amount", amount)) {
+                Err(Error::InvalidInput { .. }) => {}
+                other => panic!("amount {} gave {:?}", amount, other.map(|e| e.amount)),
+            }
+        }
+        assert!(add_expense(payload("At the cap", 1_000_000_000.0)).is_ok());
+
+        change_settings(|settings| settings.max_amount = 100.0);
+        assert!(add_expense(payload("Over a lower cap", 100.01)).is_err());
+        assert!(add_expense(payload("At a lower cap", 100.0)).is_ok());
+        assert_eq!(STORAGE.with(|service| service.borrow().len()), 2);
+    }
+
+    fn ids(expenses: &[Expense]) -> Vec<u64> {
+        expenses.iter().map(|expense| expense.id).collect()
+    }
+
+    #[test]
+    fn lists_come_newest_first_with_ties_by_id() {
+        let (monday, tuesday, wednesday) = (
+            NOW - 3 * NANOS_PER_DAY,
+            NOW - 2 * NANOS_PER_DAY,
+            NOW - NANOS_PER_DAY,
+        );
+        STORAGE.with(|service| {
+            let mut service = service.borrow_mut();
+            for (id, date, amount) in [
+                (0, tuesday, 500),
+                (1, monday, 900),
+                (2, tuesday, 500),
+                (3, wednesday, 100),
+            ] {
+                let mut expense = stored_expense(id, &format!("Taxi {}", id), amount);
+                expense.date = date;
+                expense.merchant = Some("cab co".to_string());
+                service.insert(id, expense);
+            }
+        });
+        let expected = vec![3, 2, 0, 1];
+        assert_eq!(ids(&get_my_expenses()), expected);
+        assert_eq!(ids(&search_expenses("taxi".to_string()).unwrap()), expected);
+        assert_eq!(ids(&query_expenses(ExpenseFilter::default())), expected);
+        assert_eq!(
+            ids(&get_expenses_by_merchant("Cab Co".to_string())),
+            expected
+        );
+        assert_eq!(
+            ids(&get_paginated_expenses(1, Some(10)).unwrap().items),
+            expected
+        );
+
+        // Endpoints with an order of their own break ties by the lowest id
+        assert_eq!(
+            ids(&get_expenses_sorted(SortField::Amount, SortOrder::Desc)),
+            vec![1, 0, 2, 3]
+        );
+        assert_eq!(
+            ids(&get_expenses_sorted(SortField::Amount, SortOrder::Asc)),
+            vec![3, 0, 2, 1]
+        );
+    }
+
+    #[test]
+    fn sort_helpers_compare_one_field_and_flip_for_descending() {
+        let (mut cheap, mut dear) = (stored_expense(0, "a", 100), stored_expense(1, "b", 200));
+        cheap.date = NOW;
+        dear.date = NOW - 1;
+        assert_eq!(
+            compare_by_field(&cheap, &dear, SortField::Amount),
+            Ordering::Less
+        );
+        assert_eq!(
+            compare_by_field(&cheap, &dear, SortField::Date),
+            Ordering::Greater
+        );
+        assert_eq!(
+            compare_by_field(&cheap, &dear, SortField::CreatedAt),
+            Ordering::Equal
+        );
+        assert_eq!(
+            apply_sort_order(Ordering::Less, SortOrder::Asc),
+            Ordering::Less
+        );
+        assert_eq!(
+            apply_sort_order(Ordering::Less, SortOrder::Desc),
+            Ordering::Greater
+        );
+        assert_eq!(
+            apply_sort_order(Ordering::Equal, SortOrder::Desc),
+            Ordering::Equal
+        );
+    }
+
+    #[test]
+    fn pagination_rejects_zero_and_handles_pages_past_the_end() {
+        let expenses: Vec<Expense> = (0..5).map(|id| stored_expense(id, "Snack", 100)).collect();
+        assert!(matches!(
+            paginate(expenses.clone(), 0, Some(2)),
+            Err(Error::InvalidInput { .. })
+        ));
+        assert!(matches!(
+            paginate(expenses.clone(), 1, Some(0)),
+            Err(Error::InvalidInput { .. })
+        ));
+        assert!(matches!(
+            get_paginated_expenses(0, None),
+            Err(Error::InvalidInput { .. })
+        ));
+
+        let last = paginate(expenses.clone(), 3, Some(2)).unwrap();
+        assert_eq!(ids(&last.items), vec![4]);
+        assert_eq!((last.total, last.total_pages), (5, 3));
+        let past_end = paginate(expenses.clone(), 4, Some(2)).unwrap();
+        assert!(past_end.items.is_empty());
+        assert_eq!(past_end.total, 5);
+        let huge_page = paginate(expenses.clone(), usize::MAX, Some(2)).unwrap();
+        assert!(huge_page.items.is_empty());
+
+        let defaulted = paginate(expenses.clone(), 1, None).unwrap();
+        assert_eq!(defaulted.per_page, current_settings().default_per_page);
+        let capped = paginate(expenses, 1, Some(MAX_PER_PAGE + 1)).unwrap();
+        assert_eq!(capped.per_page, MAX_PER_PAGE);
+    }
+
+    #[test]
+    fn stale_writes_are_rejected() {
+        let expense = add_expense(payload("Lunch", 12.0)).unwrap();
+        assert_eq!(expense.created_at, NOW);
+
+        advance_clock(1_000);
+        let updated = update_expense(expense.id, 0, payload("Lunch", 13.0), Some(NOW)).unwrap();
+        assert_eq!(
+            (updated.version, updated.updated_at),
+            (1, Some(NOW + 1_000))
+        );
+
+        // A client that read the expense before that update, by either guard
+        advance_clock(1_000);
+        match update_expense(expense.id, 1, payload("Lunch", 14.0), Some(NOW)) {
+            Err(Error::VersionConflict { current_version }) => assert_eq!(current_version, 1),
+            other => panic!(
+                "expected VersionConflict, got {:?}",
+                other.map(|e| e.version)
+            ),
+        }
+        assert!(matches!(
+            update_expense(expense.id, 0, payload("Lunch", 14.0), None),
+            Err(Error::VersionConflict { current_version: 1 })
+        ));
+        assert_eq!(_get_expense(&expense.id).unwrap().amount, 1300);
+
+        // Unmodified since exactly the last change is not stale
+        let mut fresh = payload("Lunch", 14.0);
+        assert!(check_update(expense.id, 1, Some(NOW + 1_000), &mut fresh).is_ok());
+        assert!(check_update(expense.id, 1, None, &mut fresh).is_ok());
+
+        CALLER.with(|caller| caller.set(Principal::from_slice(&[2])));
+        assert!(matches!(
+            check_update(expense.id, 1, None, &mut fresh),
+            Err(Error::Unauthorized { .. })
+        ));
+    }
+
+    #[test]
+    fn incoming_amounts_round_half_up_to_decimal_places() {
+        assert_eq!(round_amount(2.345), 2.35);
+        assert_eq!(round_amount(19.999999), 20.0);
+        assert_eq!(round_amount(1.005), 1.01);
+        assert_eq!(round_amount(7.994), 7.99);
+
+        let expense = add_expense(payload("Parking", 2.345)).unwrap();
+        assert_eq!(expense.amount, 235);
+        let expense = update_expense(expense.id, 0, payload("Parking", 19.999999), None).unwrap();
+        assert_eq!(expense.amount, 2000);
+        // Rounds to nothing, so it is rejected like any zero amount
+        assert!(matches!(
+            add_expense(payload("Crumbs", 0.004)),
+            Err(Error::InvalidInput { .. })
+        ));
+
+        change_settings(|settings| settings.decimal_places = 0);
+        assert_eq!(round_amount(2.5), 3.0);
+        assert_eq!(add_expense(payload("Toll", 4.49)).unwrap().amount, 400);
+    }
+
+    #[test]
+    fn restore_drops_rules_whose_category_is_gone() {
+        let snapshot = export_snapshot().unwrap();
+        let category = add_category("Transport".to_string()).unwrap();
+        add_rule("uber".to_string(), category.id).unwrap();
+        let tagged = add_expense(payload("Uber to the airport", 30.0)).unwrap();
+        assert_eq!(tagged.category_id, Some(category.id));
+
+        restore_snapshot(snapshot, RESTORE_CONFIRMATION.to_string()).unwrap();
+        assert!(list_rules().is_empty());
+        let expense = add_expense(payload("Uber home", 25.0)).unwrap();
+        assert_eq!(expense.category_id, None);
+    }
+
+    #[test]
+    fn rules_pointing_at_missing_categories_are_skipped() {
+        let category = add_category("Food".to_string()).unwrap();
+        add_rule("pizza".to_string(), category.id).unwrap();
+        // As left behind by data from before rules were cleaned up
+        CATEGORY_STORAGE.with(|service| service.borrow_mut().remove(&category.id));
+
+        let expense = add_expense(payload("Pizza night", 18.0)).unwrap();
+        assert_eq!(expense.category_id, None);
+    }
+
+    #[test]
+    fn taken_id_fails_a_single_add_cleanly() {
+        // As after importing expenses with explicit ids past the counter
+        STORAGE.with(|service| {
+            service
+                .borrow_mut()
+                .insert(0, stored_expense(0, "Imported", 100))
+        });
+        assert!(matches!(
+            add_expense(payload("Coffee", 3.0)),
+            Err(Error::StorageError { .. })
+        ));
+        assert_eq!(ID_COUNTER.with(|counter| *counter.borrow().get()), 0);
+        assert_eq!(STORAGE.with(|service| service.borrow().len()), 1);
+        assert_eq!(_get_expense(&0).unwrap().description, "Imported");
+    }
+
+    #[test]
+    fn taken_id_fails_a_batch_before_anything_is_written() {
+        let existing = add_expense(payload("Old", 1.0)).unwrap();
+        STORAGE.with(|service| {
+            service
+                .borrow_mut()
+                .insert(2, stored_expense(2, "Imported", 100))
+        });
+        let batch = vec![payload("A", 1.0), payload("B", 2.0), payload("C", 3.0)];
+        assert!(matches!(
+            add_expenses_batch(batch),
+            Err(Error::StorageError { .. })
+        ));
+        assert_eq!(ids(&get_my_expenses()), vec![2, 0]);
+        assert_eq!(ID_COUNTER.with(|counter| *counter.borrow().get()), 1);
+
+        let ops = vec![
+            ExpenseOp::Delete { id: existing.id },
+            ExpenseOp::Add {
+                payload: payload("D", 4.0),
+            },
+            ExpenseOp::Add {
+                payload: payload("E", 5.0),
+            },
+        ];
+        archive_expense(existing.id).unwrap();
+        assert!(matches!(
+            apply_operations(ops),
+            Err(Error::StorageError { .. })
+        ));
+        assert!(_get_expense(&existing.id).is_some());
+        assert_eq!(STORAGE.with(|service| service.borrow().len()), 2);
+    }
+
+    #[test]
+    fn rate_limit_counts_added_expenses() {
+        change_settings(|settings| settings.rate_limit_max_calls = 5);
+        add_expenses_batch(vec![
+            payload("A", 1.0),
+            payload("B", 2.0),
+            payload("C", 3.0),
+        ])
+        .unwrap();
+        let batch = vec![payload("D", 4.0), payload("E", 5.0), payload("F", 6.0)];
+        assert!(matches!(
+            add_expenses_batch(batch),
+            Err(Error::RateLimited { retry_after }) if retry_after == 60_000_000_000
+        ));
+        add_expense(payload("D", 4.0)).unwrap();
+        add_income(payload("Salary", 100.0)).unwrap();
+        assert!(matches!(
+            add_expense(payload("E", 5.0)),
+            Err(Error::RateLimited { .. })
+        ));
+
+        advance_clock(60_000_000_000);
+        let too_many = (0..6)
+            .map(|i| payload(&format!("Bulk {}", i), 1.0))
+            .collect();
+        assert!(matches!(
+            add_expenses_batch(too_many),
+            Err(Error::InvalidInput { .. })
+        ));
+    }
+
+    #[test]
+    fn retries_and_failed_adds_do_not_count_towards_the_rate_limit() {
+        change_settings(|settings| settings.rate_limit_max_calls = 2);
+        let mut keyed = payload("Coffee", 3.0);
+        keyed.idempotency_key = Some("coffee-1".to_string());
+        let first = add_expense(keyed.clone()).unwrap();
+        for _ in 0..3 {
+            assert_eq!(add_expense(keyed.clone()).unwrap().id, first.id);
+        }
+        assert!(add_expense(payload("Broken", -1.0)).is_err());
+        add_expense(payload("Lunch", 12.0)).unwrap();
+        assert!(matches!(
+            add_expense(payload("Dinner", 20.0)),
+            Err(Error::RateLimited { .. })
+        ));
+
+        // Once every recorded add has expired, the caller's entry goes away
+        advance_clock(60_000_000_000);
+        assert!(add_expense(payload("Broken", -1.0)).is_err());
+        assert!(RECENT_ADDS.with(|recent| recent.borrow().is_empty()));
+    }
+
+    #[test]
+    fn locked_expenses_reject_refunds_reimbursement_and_recategorizing() {
+        let mut reimbursable = payload("Coffee", 3.0);
+        reimbursable.reimbursable = Some(true);
+        let locked = add_expense(reimbursable).unwrap();
+        let mut recent = payload("Coffee beans", 12.0);
+        recent.date = NOW;
+        let open = add_expense(recent).unwrap();
+        lock_period(NOW - NANOS_PER_DAY / 2).unwrap();
+        let category = add_category("Food".to_string()).unwrap();
+        add_rule("Coffee".to_string(), category.id).unwrap();
+
+        assert!(matches!(
+            mark_reimbursed(locked.id),
+            Err(Error::PeriodLocked { .. })
+        ));
+        assert!(matches!(
+            add_refund(locked.id, 1.0, NOW),
+            Err(Error::PeriodLocked { .. })
+        ));
+        assert!(matches!(
+            recategorize_expenses(None, Some(category.id)),
+            Err(Error::PeriodLocked { .. })
+        ));
+        assert!(matches!(
+            apply_rules_to_existing(),
+            Err(Error::PeriodLocked { .. })
+        ));
+        assert_eq!(_get_expense(&open.id).unwrap().category_id, None);
+
+        let mut orphaned = _get_expense(&locked.id).unwrap();
+        orphaned.category_id = Some(category.id + 1);
+        do_insert(&orphaned).unwrap();
+        assert!(matches!(
+            clean_orphaned_categories(),
+            Err(Error::PeriodLocked { .. })
+        ));
+        assert_eq!(
+            _get_expense(&locked.id).unwrap().category_id,
+            Some(category.id + 1)
+        );
+
+        unlock_period().unwrap();
+        assert_eq!(apply_rules_to_existing().unwrap(), 1);
+        assert!(mark_reimbursed(locked.id).is_ok());
+    }
+
+    #[test]
+    fn post_upgrade_converts_stored_dates_once() {
+        let mut expense = stored_expense(0, "Rent", 50_000);
+        expense.date = 1_700_000_000; // Seconds
+        STORAGE.with(|service| service.borrow_mut().insert(0, expense.clone()));
+        let refund = Refund {
+            expense_id: 0,
+            amount: 1_000,
+            date: 1_700_000_000_000, // Milliseconds
+            created_at: NOW,
+        };
+        REFUND_STORAGE.with(|service| service.borrow_mut().insert((0, 0), refund));
+        let template = RecurringTemplate {
+            id: 0,
+            owner: caller(),
+            description: "Gym".to_string(),
+            amount: 3_000,
+            currency: "USD".to_string(),
+            interval_days: 30,
+            next_due: 1_800_000_000,
+            category_id: None,
+        };
+        RECURRING_STORAGE.with(|service| service.borrow_mut().insert(0, template));
+        let planned = PlannedExpense {
+            id: 0,
+            owner: caller(),
+            description: "Insurance".to_string(),
+            amount: 40_000,
+            currency: "USD".to_string(),
+            due_date: 1_800_000_000_000,
+            paid: false,
+            expense_id: None,
+        };
+        PLANNED_STORAGE.with(|service| service.borrow_mut().insert(0, planned));
+
+        post_upgrade();
+        assert_eq!(
+            _get_expense(&0).unwrap().date,
+            1_700_000_000 * 1_000_000_000
+        );
+        assert_eq!(
+            REFUND_STORAGE.with(|service| service.borrow().get(&(0, 0)).unwrap().date),
+            1_700_000_000 * 1_000_000_000
+        );
+        assert_eq!(
+            RECURRING_STORAGE.with(|service| service.borrow().get(&0).unwrap().next_due),
+            1_800_000_000 * 1_000_000_000
+        );
+        assert_eq!(
+            PLANNED_STORAGE.with(|service| service.borrow().get(&0).unwrap().due_date),
+            1_800_000_000 * 1_000_000_000
+        );
+
+        // Later upgrades no longer scan the stored records
+        STORAGE.with(|service| service.borrow_mut().insert(0, expense));
+        post_upgrade();
+        assert_eq!(_get_expense(&0).unwrap().date, 1_700_000_000);
+    }
+
+    #[test]
+    fn spending_reports_are_net_of_refunds() {
+        let food = add_category("Food".to_string()).unwrap();
+        let home = add_category("Home".to_string()).unwrap();
+        let mut groceries = payload("Groceries", 100.0);
+        groceries.category_id = Some(food.id);
+        let groceries = add_expense(groceries).unwrap();
+        let mut shopping = payload("Shopping", 100.0);
+        shopping.category_splits = Some(vec![
+            CategorySplitPayload {
+                category_id: food.id,
+                amount: 60.0,
+            },
+            CategorySplitPayload {
+                category_id: home.id,
+                amount: 40.0,
+            },
+        ]);
+        let shopping = add_expense(shopping).unwrap();
+        approve(&groceries);
+        approve(&shopping);
+        add_refund(groceries.id, 40.0, NOW).unwrap();
+        add_refund(shopping.id, 10.0, NOW).unwrap();
+
+        let (year, month, _) = civil_date_from_nanos(groceries.date);
+        let monthly = get_monthly_totals(None).unwrap();
+        assert_eq!(monthly.len(), 1);
+        assert_eq!(monthly[0].total, 150.0);
+        let daily = get_daily_totals(year, month, None).unwrap();
+        assert_eq!(daily[0].total, 150.0);
+        let weekday_total: f64 = get_spending_by_weekday()
+            .iter()
+            .map(|weekday| weekday.total)
+            .sum();
+        assert_eq!(weekday_total, 150.0);
+
+        let stats = get_expense_statistics();
+        assert_eq!((stats.total, stats.min, stats.max), (150.0, 60.0, 90.0));
+        let summary = get_period_summary(groceries.date, groceries.date).unwrap();
+        assert_eq!(summary.total, 150.0);
+        let top = summary.top_category.unwrap();
+        assert_eq!((top.category_id, top.total), (Some(food.id), 114.0));
+
+        set_budget(home.id, 50.0).unwrap();
+        let status = check_budget_status(home.id, year, month).unwrap();
+        assert_eq!((status.spent, status.remaining), (36.0, 14.0));
+    }
+}